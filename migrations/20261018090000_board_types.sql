@@ -0,0 +1,21 @@
+--
+-- Copyright (C) 2024, HENSOLDT Cyber GmbH
+-- 
+-- SPDX-License-Identifier: GPL-2.0-or-later
+--
+-- For commercial licensing, contact: info.cyber@hensoldt.net
+--
+
+
+-- BoardTypes Table
+CREATE TABLE BoardTypes (
+    Id TEXT PRIMARY KEY NOT NULL
+);
+
+-- Every board belongs to at most one pool of identical boards
+ALTER TABLE Hardware ADD COLUMN Type TEXT REFERENCES BoardTypes (Id);
+
+
+-- Existing boards form a pool of their own
+INSERT INTO BoardTypes (Id) SELECT Id FROM Hardware;
+UPDATE Hardware SET Type = Id;
@@ -0,0 +1,20 @@
+--
+-- Copyright (C) 2024, HENSOLDT Cyber GmbH
+-- 
+-- SPDX-License-Identifier: GPL-2.0-or-later
+--
+-- For commercial licensing, contact: info.cyber@hensoldt.net
+--
+
+
+-- HardwareQueue Table
+-- Claim requests waiting for a board, granted in order of Id
+CREATE TABLE HardwareQueue (
+    Id INTEGER PRIMARY KEY AUTOINCREMENT,
+    Hardware TEXT NOT NULL,
+    Runner TEXT NOT NULL,
+    EnqueuedAt TIMESTAMP NOT NULL,
+    UNIQUE (Hardware, Runner),
+    FOREIGN KEY (Hardware) REFERENCES Hardware (Id) ON DELETE CASCADE,
+    FOREIGN KEY (Runner) REFERENCES RunnerVMs (Id) ON DELETE CASCADE
+);
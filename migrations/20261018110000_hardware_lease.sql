@@ -0,0 +1,11 @@
+--
+-- Copyright (C) 2024, HENSOLDT Cyber GmbH
+-- 
+-- SPDX-License-Identifier: GPL-2.0-or-later
+--
+-- For commercial licensing, contact: info.cyber@hensoldt.net
+--
+
+
+-- Claims are only valid until their lease expires, NULL for no lease
+ALTER TABLE Hardware ADD COLUMN LeaseExpiry TIMESTAMP;
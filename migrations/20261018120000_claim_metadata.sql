@@ -0,0 +1,20 @@
+--
+-- Copyright (C) 2024, HENSOLDT Cyber GmbH
+-- 
+-- SPDX-License-Identifier: GPL-2.0-or-later
+--
+-- For commercial licensing, contact: info.cyber@hensoldt.net
+--
+
+
+-- GitHub workflow a board is claimed for
+ALTER TABLE Hardware ADD COLUMN ClaimRepository TEXT;
+ALTER TABLE Hardware ADD COLUMN ClaimRunId INTEGER;
+ALTER TABLE Hardware ADD COLUMN ClaimJob TEXT;
+ALTER TABLE Hardware ADD COLUMN ClaimSha TEXT;
+
+-- Queued claims carry their metadata until they are granted
+ALTER TABLE HardwareQueue ADD COLUMN ClaimRepository TEXT;
+ALTER TABLE HardwareQueue ADD COLUMN ClaimRunId INTEGER;
+ALTER TABLE HardwareQueue ADD COLUMN ClaimJob TEXT;
+ALTER TABLE HardwareQueue ADD COLUMN ClaimSha TEXT;
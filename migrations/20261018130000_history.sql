@@ -0,0 +1,23 @@
+--
+-- Copyright (C) 2024, HENSOLDT Cyber GmbH
+-- 
+-- SPDX-License-Identifier: GPL-2.0-or-later
+--
+-- For commercial licensing, contact: info.cyber@hensoldt.net
+--
+
+
+-- History Table
+-- Append-only log of runner and hardware state changes. No foreign keys, so the
+-- history of deleted runners and boards is kept.
+CREATE TABLE History (
+    Id INTEGER PRIMARY KEY AUTOINCREMENT,
+    Time TIMESTAMP NOT NULL,
+    Kind TEXT CHECK(Kind IN ('RUNNER', 'HARDWARE')) NOT NULL,
+    Subject TEXT NOT NULL,
+    Event TEXT NOT NULL,
+    Value TEXT,
+    Runner TEXT
+);
+
+CREATE INDEX HistoryBySubject ON History (Kind, Subject, Time);
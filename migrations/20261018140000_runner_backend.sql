@@ -0,0 +1,11 @@
+--
+-- Copyright (C) 2024, HENSOLDT Cyber GmbH
+-- 
+-- SPDX-License-Identifier: GPL-2.0-or-later
+--
+-- For commercial licensing, contact: info.cyber@hensoldt.net
+--
+
+
+-- VM backend controlling the runner, NULL for the one selected via VM_BACKEND
+ALTER TABLE RunnerVMs ADD COLUMN Backend TEXT;
@@ -0,0 +1,15 @@
+--
+-- Copyright (C) 2024, HENSOLDT Cyber GmbH
+-- 
+-- SPDX-License-Identifier: GPL-2.0-or-later
+--
+-- For commercial licensing, contact: info.cyber@hensoldt.net
+--
+
+
+-- Last command sent to the runner VM and its outcome
+ALTER TABLE RunnerVMs ADD COLUMN LastCommand TEXT;
+ALTER TABLE RunnerVMs ADD COLUMN LastCommandStatus TEXT
+    CHECK(LastCommandStatus IN ('PENDING', 'DONE', 'FAILED', 'TIMEOUT'));
+ALTER TABLE RunnerVMs ADD COLUMN LastCommandMessage TEXT;
+ALTER TABLE RunnerVMs ADD COLUMN LastCommandTime TIMESTAMP;
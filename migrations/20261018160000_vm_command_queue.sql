@@ -0,0 +1,25 @@
+--
+-- Copyright (C) 2024, HENSOLDT Cyber GmbH
+-- 
+-- SPDX-License-Identifier: GPL-2.0-or-later
+--
+-- For commercial licensing, contact: info.cyber@hensoldt.net
+--
+
+
+-- VmCommands Table
+-- Commands for the runner VMs, executed in order of Id per runner by the VM command task
+CREATE TABLE VmCommands (
+    Id INTEGER PRIMARY KEY AUTOINCREMENT,
+    Runner TEXT NOT NULL,
+    Command TEXT NOT NULL,
+    Status TEXT CHECK(Status IN ('PENDING', 'SENT', 'ACKNOWLEDGED', 'FAILED')) NOT NULL,
+    Attempts INTEGER NOT NULL DEFAULT 0,
+    NotBefore TIMESTAMP NOT NULL,
+    LastError TEXT,
+    CreatedAt TIMESTAMP NOT NULL,
+    UpdatedAt TIMESTAMP NOT NULL,
+    FOREIGN KEY (Runner) REFERENCES RunnerVMs (Id) ON DELETE CASCADE
+);
+
+CREATE INDEX VmCommandsByStatus ON VmCommands (Status, Runner);
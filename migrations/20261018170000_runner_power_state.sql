@@ -0,0 +1,13 @@
+--
+-- Copyright (C) 2024, HENSOLDT Cyber GmbH
+-- 
+-- SPDX-License-Identifier: GPL-2.0-or-later
+--
+-- For commercial licensing, contact: info.cyber@hensoldt.net
+--
+
+
+-- Power state of the runner VM as last reported by its backend
+ALTER TABLE RunnerVMs ADD COLUMN PowerState TEXT
+    CHECK(PowerState IN ('RUNNING', 'STOPPED', 'CRASHED', 'UNKNOWN'));
+ALTER TABLE RunnerVMs ADD COLUMN PowerStateTime TIMESTAMP;
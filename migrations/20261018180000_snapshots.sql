@@ -0,0 +1,27 @@
+--
+-- Copyright (C) 2024, HENSOLDT Cyber GmbH
+-- 
+-- SPDX-License-Identifier: GPL-2.0-or-later
+--
+-- For commercial licensing, contact: info.cyber@hensoldt.net
+--
+
+
+-- Snapshots Table
+-- Named snapshots of the runner VMs, Available once the backend acknowledged them
+CREATE TABLE Snapshots (
+    Id INTEGER PRIMARY KEY AUTOINCREMENT,
+    Runner TEXT NOT NULL,
+    Name TEXT NOT NULL,
+    BaseImage TEXT,
+    CreatedAt TIMESTAMP NOT NULL,
+    Available BOOLEAN NOT NULL DEFAULT 0,
+    UNIQUE (Runner, Name),
+    FOREIGN KEY (Runner) REFERENCES RunnerVMs (Id) ON DELETE CASCADE
+);
+
+-- Snapshot resets go back to, NULL for the backend's current one
+ALTER TABLE RunnerVMs ADD COLUMN CurrentSnapshot TEXT;
+
+-- Snapshot name of the snapshot commands
+ALTER TABLE VmCommands ADD COLUMN Argument TEXT;
@@ -0,0 +1,12 @@
+--
+-- Copyright (C) 2024, HENSOLDT Cyber GmbH
+-- 
+-- SPDX-License-Identifier: GPL-2.0-or-later
+--
+-- For commercial licensing, contact: info.cyber@hensoldt.net
+--
+
+
+-- Time of the last snapshot, existing runners start their schedule now
+ALTER TABLE RunnerVMs ADD COLUMN LastSnapshot TIMESTAMP;
+UPDATE RunnerVMs SET LastSnapshot = CURRENT_TIMESTAMP;
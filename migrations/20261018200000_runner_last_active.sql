@@ -0,0 +1,11 @@
+--
+-- Copyright (C) 2024, HENSOLDT Cyber GmbH
+-- 
+-- SPDX-License-Identifier: GPL-2.0-or-later
+--
+-- For commercial licensing, contact: info.cyber@hensoldt.net
+--
+
+
+-- Last time the runner registered with GitHub or launched a job
+ALTER TABLE RunnerVMs ADD COLUMN LastActive TIMESTAMP;
@@ -0,0 +1,15 @@
+--
+-- Copyright (C) 2024, HENSOLDT Cyber GmbH
+-- 
+-- SPDX-License-Identifier: GPL-2.0-or-later
+--
+-- For commercial licensing, contact: info.cyber@hensoldt.net
+--
+
+
+-- Workflow job the runner works on, reported by the GitHub webhook
+ALTER TABLE RunnerVMs ADD COLUMN JobId INTEGER;
+ALTER TABLE RunnerVMs ADD COLUMN JobRunId INTEGER;
+ALTER TABLE RunnerVMs ADD COLUMN JobName TEXT;
+ALTER TABLE RunnerVMs ADD COLUMN JobRepository TEXT;
+ALTER TABLE RunnerVMs ADD COLUMN JobUrl TEXT;
@@ -0,0 +1,13 @@
+--
+-- Copyright (C) 2024, HENSOLDT Cyber GmbH
+-- 
+-- SPDX-License-Identifier: GPL-2.0-or-later
+--
+-- For commercial licensing, contact: info.cyber@hensoldt.net
+--
+
+
+-- Comma separated labels and runner group id for just-in-time configs, NULL for the ones
+-- selected via RUNNER_LABELS and RUNNER_GROUP_ID
+ALTER TABLE RunnerVMs ADD COLUMN Labels TEXT;
+ALTER TABLE RunnerVMs ADD COLUMN RunnerGroup INTEGER;
@@ -0,0 +1,12 @@
+--
+-- Copyright (C) 2024, HENSOLDT Cyber GmbH
+-- 
+-- SPDX-License-Identifier: GPL-2.0-or-later
+--
+-- For commercial licensing, contact: info.cyber@hensoldt.net
+--
+
+
+-- Organization or owner/repo the runner registers with, NULL for the one selected via
+-- GITHUB_ORG or GITHUB_OWNER/GITHUB_REPO
+ALTER TABLE RunnerVMs ADD COLUMN Scope TEXT;
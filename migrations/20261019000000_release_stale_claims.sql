@@ -0,0 +1,11 @@
+--
+-- Copyright (C) 2024, HENSOLDT Cyber GmbH
+-- 
+-- SPDX-License-Identifier: GPL-2.0-or-later
+--
+-- For commercial licensing, contact: info.cyber@hensoldt.net
+--
+
+
+-- Releases used to keep ClaimedBy, which blocks deleting the runner through the foreign key
+UPDATE Hardware SET ClaimedBy = NULL WHERE Status != 'CLAIMED';
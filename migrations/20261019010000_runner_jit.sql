@@ -0,0 +1,11 @@
+--
+-- Copyright (C) 2024, HENSOLDT Cyber GmbH
+-- 
+-- SPDX-License-Identifier: GPL-2.0-or-later
+--
+-- For commercial licensing, contact: info.cyber@hensoldt.net
+--
+
+
+-- Runners registered with a just-in-time config are removed by GitHub after their job
+ALTER TABLE RunnerVMs ADD COLUMN Jit BOOLEAN NOT NULL DEFAULT 0;
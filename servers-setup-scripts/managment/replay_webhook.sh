@@ -0,0 +1,22 @@
+#!/bin/bash
+# Replays a recorded GitHub webhook payload against the API, signed like GitHub does.
+# Payloads can be copied from "Recent Deliveries" in the webhook settings on GitHub.
+
+IP="${IP:-127.0.0.1}"
+PORT="${PORT:-8000}"
+
+if [ -z "$1" ] || [ -z "$2" ] || [ -z "$GITHUB_WEBHOOK_SECRET" ]; then
+	echo "Usage: GITHUB_WEBHOOK_SECRET=<secret> $0 <event> <payload.json>"
+	echo "       e.g. $0 workflow_job completed.json"
+	exit 1
+fi
+
+signature=$(openssl dgst -sha256 -hmac "$GITHUB_WEBHOOK_SECRET" -r < "$2" | cut -d' ' -f1)
+
+curl -s -w "\nHTTP status code %{http_code}\n" -X POST \
+	-H "Content-Type: application/json" \
+	-H "X-GitHub-Event: $1" \
+	-H "X-GitHub-Delivery: replay-$(date +%s)" \
+	-H "X-Hub-Signature-256: sha256=$signature" \
+	--data-binary "@$2" \
+	"http://$IP:$PORT/github/webhook"
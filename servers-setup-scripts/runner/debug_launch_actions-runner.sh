@@ -10,8 +10,10 @@ if [ ! -f "/etc/runner_id" ]; then
 fi
 runner_id=$(tr -d '\n ' < /etc/runner_id)
 
-# retrieve one time token from tokenserver
-token=$(curl -s http://$IP:$PORT/runner/$runner_id/registration-token | tr -d '"\n')
+# retrieve one time token and the URL to register at from tokenserver
+registration=$(curl -s http://$IP:$PORT/runner/$runner_id/registration)
+token=$(echo "$registration" | sed -n 's/.*"token":"\([^"]*\)".*/\1/p')
+url=$(echo "$registration" | sed -n 's/.*"url":"\([^"]*\)".*/\1/p')
 
 cd /home/actions-service-user/actions-runner
 
@@ -22,5 +24,5 @@ export https_proxy='http://proxy.cc.ebs.corp:8080'
 export http_proxy='http://proxy.cc.ebs.corp:8080'
 export SSL_CERT_DIR='/etc/ssl/certs'
 
-./config.sh --url https://github.com/TRENT-OS --token $token --name $runner_id --unattended --replace --disableupdate --check
+./config.sh --url $url --token $token --name $runner_id --unattended --replace --disableupdate --check
 ./run.sh
\ No newline at end of file
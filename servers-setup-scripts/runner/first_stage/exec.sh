@@ -1,14 +1,11 @@
 #!/bin/bash
 
-# Make new snapshot to lighten update load
-#			   days hours min secs
-TIME_TO_RESNAP=$[ 30 * 24 * 60 * 60 ]   # = 30 days
+# New snapshots to lighten the update load are taken right after the update, before the
+# runner registers. The management API decides whether one is due.
 
 IP="10.70.192.2"
 PORT=8000
 
-SNAPSHOT_STMP="/usr/local/libexec/setup_first_stage/last_snapshot"
-
 # check if run with root privileges
 if [ "$EUID" -ne 0 ]; then
 	echo "Please run as root"
@@ -17,31 +14,20 @@ fi
 
 runner_id=$(tr -d '\n ' < /etc/runner_id)
 
-# check if file exists
-if [ ! -f $SNAPSHOT_STMP ]; then
-	echo 0 > $SNAPSHOT_STMP
-fi
-last_reset=$(tr -d '\n ' < $SNAPSHOT_STMP)
-
-
-url="http://$IP:$PORT/runner/$runner_id"
-url_snap="$url/vm/snapshot"
+url_snap_due="http://$IP:$PORT/runner/$runner_id/vm/snapshot/due"
 
 dnf upgrade -y
 
-if (( $(date +%s) - last_reset > TIME_TO_RESNAP )); then
-	status_code=$(curl -X POST -o /dev/null -w "%{http_code}" -s "$url_snap")
-	if [ "$status_code" -ne 200 ]; then
-		echo "Failed: HTTP status code $status_code"
-		reboot
-	fi
-
-	date +%s > $SNAPSHOT_STMP
-
+status_code=$(curl -X POST -o /dev/null -w "%{http_code}" -s "$url_snap_due")
+if [ "$status_code" == "202" ]; then
 	# We just leave the script and idle...
 	# -> snapshot will be created and the server will be reset.
 	exit 0
 fi
+if [ "$status_code" != "204" ]; then
+	echo "Failed: HTTP status code $status_code"
+	reboot
+fi
 
 # enable the service, so that it is run after reboot
 systemctl enable setup_second_stage.service
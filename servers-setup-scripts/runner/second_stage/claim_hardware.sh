@@ -7,51 +7,84 @@ dt_i=0
 IP="10.70.192.2"
 PORT=8000
 
-if [ -z "$1" ]; then
+if [ -z "$1" ] || { [ "$1" == "--pool" ] && [ -z "$2" ]; }; then
   echo "Usage: $0 <board>"
+  echo "       $0 --pool <board type>"
   exit 1
 fi
 
 runner_id=$(tr -d '\n ' < /tmp/runner_id)
 
-# URL to send the curl request to
-url="http://$IP:$PORT/hardware/$1"
+# Workflow the claim is made for, provided by the actions runner
+metadata=$(jq -nc \
+	--arg repository "$GITHUB_REPOSITORY" \
+	--arg run_id "$GITHUB_RUN_ID" \
+	--arg job "$GITHUB_JOB" \
+	--arg sha "$GITHUB_SHA" \
+	'{repository: $repository, run_id: ($run_id | tonumber? // null), job: $job, sha: $sha}')
 
-url_available="$url/available"
-url_claim="$url/claim/$runner_id"
+# Single boards: the API queues us and answers once the board is ours, or with
+# 408 after the timeout, in which case we simply ask again.
+if [ "$1" != "--pool" ]; then
+	url_wait="http://$IP:$PORT/hardware/$1/claim/$runner_id/wait?timeout=60"
+	url_queue="http://$IP:$PORT/hardware/$1/queue/$runner_id"
 
-while true; do
-	response=$(curl -s -w "%{http_code}" "$url_available")
-    status_code="${response: -3}"
-    response_body="${response::-3}"
-
-	# Hardware is free
-	if [ "$status_code" == "200" ] && [ "$response_body" == "true" ]; then
-		#claim it
-		claim_code=$(curl -X POST -s -o /dev/null -w "%{http_code}" "$url_claim")
-		if [ "$claim_code" -ne 200 ]; then
-			echo "Failed: HTTP status code $claim_code"
+	status_code=$(curl -X POST -s -o /dev/null -w "%{http_code}" \
+		-H "Content-Type: application/json" -d "$metadata" "$url_queue")
+	if [ "$status_code" != "200" ]; then
+		echo "Failed: HTTP status code $status_code"
+		exit 1
+	fi
+
+	while true; do
+		status_code=$(curl -s -o /dev/null -w "%{http_code}" "$url_wait")
+
+		if [ "$status_code" == "200" ]; then
+			echo "Claimed hardware: $1"
+			exit 0
+		fi
+
+		if [ "$status_code" != "408" ]; then
+			echo "Failed: HTTP status code $status_code"
 			exit 1
 		fi
 
+		echo "Hardware $1 is not available, still waiting"
+	done
+fi
+
+# Pools: any free board of the given type is claimed, the API tells us which one
+url_claim="http://$IP:$PORT/hardware/pool/$2/claim/$runner_id"
+
+while true; do
+	response=$(curl -X POST -s -w "%{http_code}" \
+		-H "Content-Type: application/json" -d "$metadata" "$url_claim")
+	status_code="${response: -3}"
+	response_body="${response::-3}"
+
+	# Hardware was claimed
+	if [ "$status_code" == "200" ]; then
 		if [ "$dt_i" -ne 0 ]; then
 			echo -ne "\n"
 		fi
-		echo "Claimed hardware: $1"
-        exit 0
-    fi
 
-	if [ "$status_code" != "200" ]; then
-        echo "Failed: HTTP status code $status_code"
-        exit 1
-    fi
+		board=$(echo "$response_body" | sed -n 's/.*"name":"\([^"]*\)".*/\1/p')
+		echo "Claimed hardware: $board"
+		exit 0
+	fi
+
+	# Anything but "all boards claimed" is an error
+	if [ "$status_code" != "409" ]; then
+		echo "Failed: HTTP status code $status_code"
+		exit 1
+	fi
 
 	for i in {1..5}; do
-    	echo -ne "\rHardware $1 is not available, waiting${dot_sequence[$dot_index]}   "
+		echo -ne "\rNo hardware of type $2 is available, waiting${dot_sequence[$dot_index]}   "
 		dot_index=$(( (dot_index + 1) % 3 ))
 		sleep 1
 	done
 
 	#Loop till force reset or hardware is available
 	dt_i=$(( dt_i + 5 ))
-done
\ No newline at end of file
+done
@@ -1,5 +1,5 @@
 #!/bin/bash
-# retrieve one time token from tokenserver
+# retrieve one time token or just-in-time config from tokenserver
 
 IP="10.70.192.2"
 PORT=8000
@@ -10,7 +10,27 @@ if [ ! -f "/etc/runner_id" ]; then
 fi
 runner_id=$(tr -d '\n ' < /etc/runner_id)
 
-export TOKEN=$(curl -s http://$IP:$PORT/runner/${runner_id}/registration-token | tr -d '"\n')
+# Just-in-time runners are registered by the API, nothing is left behind on GitHub when
+# the VM is reverted. Set to 1 to use them instead of registering with a token via
+# config.sh.
+USE_JITCONFIG=${USE_JITCONFIG:-0}
+
+if [ "$USE_JITCONFIG" = "1" ]; then
+    JITCONFIG=$(curl -sf http://$IP:$PORT/runner/${runner_id}/jitconfig | tr -d '"\n')
+    if [ -z "$JITCONFIG" ]; then
+        echo "Failure: No just-in-time config for runner ${runner_id}"
+        exit 1
+    fi
+else
+    # The token comes with the organization or repository URL of the runner's scope
+    registration=$(curl -sf http://$IP:$PORT/runner/${runner_id}/registration)
+    export TOKEN=$(echo "$registration" | sed -n 's/.*"token":"\([^"]*\)".*/\1/p')
+    RUNNER_URL=$(echo "$registration" | sed -n 's/.*"url":"\([^"]*\)".*/\1/p')
+    if [ -z "$TOKEN" ] || [ -z "$RUNNER_URL" ]; then
+        echo "Failure: No registration for runner ${runner_id}"
+        exit 1
+    fi
+fi
 
 cd /home/actions-service-user/actions-runner
 
@@ -24,5 +44,9 @@ export SSL_CERT_DIR='/etc/ssl/certs'
 export DOTNET_SYSTEM_NET_HTTP_USESOCKETSHTTPHANDLER=0
 export GITHUB_ACTIONS_RUNNER_TLS_NO_VERIFY=1
 
-./config.sh --url https://github.com/TRENT-OS --token $TOKEN --name $runner_id --unattended --ephemeral --replace
-./run.sh
+if [ "$USE_JITCONFIG" = "1" ]; then
+    ./run.sh --jitconfig "$JITCONFIG"
+else
+    ./config.sh --url $RUNNER_URL --token $TOKEN --name $runner_id --unattended --ephemeral --replace
+    ./run.sh
+fi
@@ -0,0 +1,99 @@
+//
+// Copyright (C) 2024, HENSOLDT Cyber GmbH
+// 
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// For commercial licensing, contact: info.cyber@hensoldt.net
+//
+
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    tokio::time::{interval, Duration},
+    Build, Rocket,
+};
+use rocket_db_pools::{sqlx::SqlitePool, Database};
+use chrono::Utc;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use crate::{db, runners, vm};
+
+//------------------------------------------------------------------------------
+// Command Queue Logic
+//------------------------------------------------------------------------------
+
+
+/// Runners are handled concurrently on their own connection, so a slow backend or GitHub
+/// only holds up the commands of its runner. A runner is not picked up again while its
+/// last command is still being processed.
+async fn command_task(pool: SqlitePool) {
+    let busy: Arc<Mutex<HashSet<String>>> = Arc::new(Mutex::new(HashSet::new()));
+    let mut interval = interval(Duration::from_secs(5));
+    loop {
+        interval.tick().await;
+
+        let mut db = match pool.acquire().await {
+            Ok(db) => db,
+            Err(e) => {
+                eprintln!("Failed to acquire a database connection: {}", e);
+                continue;
+            }
+        };
+
+        let retained = Utc::now().naive_utc() - chrono::Duration::seconds(*vm::COMMAND_RETENTION);
+        db::delete_finished_vm_commands(&mut *db, retained).await;
+
+        // The queue lives in the database, so commands pending before a restart resume here
+        let commands = db::next_vm_commands(&mut *db).await;
+        drop(db);
+
+        for command in commands {
+            let runner = command.Runner.clone();
+            if !busy.lock().unwrap().insert(runner.clone()) {
+                continue;
+            }
+
+            let pool = pool.clone();
+            let busy = busy.clone();
+            rocket::tokio::spawn(async move {
+                match pool.acquire().await {
+                    Ok(mut db) => runners::process_vm_command(&mut *db, command).await,
+                    Err(e) => eprintln!("Failed to acquire a database connection: {}", e),
+                }
+                busy.lock().unwrap().remove(&runner);
+            });
+        }
+    }
+}
+
+
+
+//------------------------------------------------------------------------------
+// Fairing Setup
+//------------------------------------------------------------------------------
+
+
+pub struct VmCommandTask;
+
+#[rocket::async_trait]
+impl Fairing for VmCommandTask {
+    fn info(&self) -> Info {
+        Info {
+            name: "VM Command Task",
+            kind: Kind::Ignite | Kind::Liftoff,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> Result<Rocket<Build>, Rocket<Build>> {
+        let db_pool = match db::RunnerDb::fetch(&rocket) {
+            Some(pool) => pool,
+            None => {
+                eprintln!("Failed to fetch the database pool");
+                return Err(rocket);
+            }
+        };
+        rocket::tokio::spawn(command_task((**db_pool).clone()));
+        Ok(rocket)
+    }
+}
@@ -16,7 +16,7 @@ use rocket_okapi::okapi::{schemars, schemars::JsonSchema};
 use std::str::FromStr;
 use strum_macros::{AsRefStr, EnumString};
 
-use crate::{hardware, runners, timestamp};
+use crate::{github, hardware, runners, timestamp, transition, vm};
 
 //------------------------------------------------------------------------------
 // Data Structures
@@ -43,6 +43,101 @@ pub enum HardwareStatus {
     ERROR,
 }
 
+#[derive(Debug, AsRefStr, PartialEq, EnumString, Serialize, Deserialize, JsonSchema)]
+pub enum CommandStatus {
+    PENDING,
+    DONE,
+    FAILED,
+    TIMEOUT,
+}
+
+#[derive(Debug, AsRefStr, PartialEq, EnumString, Serialize, Deserialize, JsonSchema)]
+pub enum VmCommandStatus {
+    PENDING,
+    SENT,
+    ACKNOWLEDGED,
+    FAILED,
+}
+
+#[derive(Debug, AsRefStr, PartialEq, EnumString, Serialize, Deserialize, JsonSchema)]
+pub enum HistoryKind {
+    RUNNER,
+    HARDWARE,
+}
+
+#[derive(Debug, AsRefStr, PartialEq, EnumString, Serialize, Deserialize, JsonSchema)]
+#[allow(non_camel_case_types)]
+pub enum HistoryEvent {
+    STATUS,
+    TIME_TO_RESET,
+    FORCE_RESET,
+    QUEUED,
+    VM_COMMAND_FAILED,
+    POWER_STATE,
+    IDLE_RESET,
+    JOB_STARTED,
+    JOB_COMPLETED,
+    DEREGISTER_FAILED,
+}
+
+#[derive(sqlx::FromRow)]
+#[allow(non_snake_case)]
+pub struct HistoryRecord {
+    pub Time: chrono::NaiveDateTime,
+    pub Subject: String,
+    pub Event: String,
+    pub Value: Option<String>,
+    pub Runner: Option<String>,
+}
+
+#[derive(sqlx::FromRow)]
+#[allow(non_snake_case)]
+pub struct VmCommandRecord {
+    pub Id: i64,
+    pub Runner: String,
+    pub Command: String,
+    pub Argument: Option<String>,
+    pub Status: String,
+    pub Attempts: i64,
+    pub NotBefore: chrono::NaiveDateTime,
+    pub LastError: Option<String>,
+    pub CreatedAt: chrono::NaiveDateTime,
+    pub UpdatedAt: chrono::NaiveDateTime,
+}
+
+#[derive(sqlx::FromRow)]
+#[allow(non_snake_case)]
+pub struct SnapshotRecord {
+    pub Name: String,
+    pub BaseImage: Option<String>,
+    pub CreatedAt: chrono::NaiveDateTime,
+    pub Available: bool,
+}
+
+#[derive(sqlx::FromRow)]
+#[allow(non_snake_case)]
+struct JobRecord {
+    JobId: Option<i64>,
+    JobRunId: Option<i64>,
+    JobName: Option<String>,
+    JobRepository: Option<String>,
+    JobUrl: Option<String>,
+}
+
+#[derive(sqlx::FromRow)]
+#[allow(non_snake_case)]
+struct HardwareRecord {
+    Id: String,
+    Status: String,
+    ClaimedBy: Option<String>,
+    Type: Option<String>,
+    LeaseExpiry: Option<chrono::NaiveDateTime>,
+    ClaimRepository: Option<String>,
+    ClaimRunId: Option<i64>,
+    ClaimJob: Option<String>,
+    ClaimSha: Option<String>,
+}
+
 //------------------------------------------------------------------------------
 // Runner
 //------------------------------------------------------------------------------
@@ -56,13 +151,62 @@ pub async fn runner_exists(db: &mut SqliteConnection, runner: &str) -> bool {
         .0
 }
 
-pub async fn update_runner_status(db: &mut SqliteConnection, runner: &str, status: RunnerStatus) {
+pub async fn insert_runner(db: &mut SqliteConnection, runner: &str, status: RunnerStatus) {
+    // The VM is expected to come with a fresh snapshot
+    sqlx::query(
+        "INSERT INTO RunnerVMs (Id, Status, TimeToReset, LastSnapshot) VALUES (?, ?, NULL, ?)",
+    )
+    .bind(runner)
+    .bind(status.as_ref())
+    .bind(chrono::Utc::now().naive_utc())
+    .execute(db)
+    .await
+    .unwrap();
+}
+
+pub async fn delete_runner(db: &mut SqliteConnection, runner: &str) {
+    sqlx::query("DELETE FROM RunnerVMs WHERE Id = ?")
+        .bind(runner)
+        .execute(db)
+        .await
+        .unwrap();
+}
+
+pub async fn update_runner_status(
+    db: &mut SqliteConnection,
+    runner: &str,
+    status: RunnerStatus,
+) -> Result<(), transition::TransitionError> {
+    let previous = get_runner_status(db, runner).await;
+    transition::check_runner(runner, &previous, &status)?;
+
     sqlx::query("UPDATE RunnerVMs SET Status = ? WHERE Id = ?")
         .bind(status.as_ref())
         .bind(runner)
-        .execute(db)
+        .execute(&mut *db)
         .await
         .unwrap();
+
+    insert_history(
+        db,
+        HistoryKind::RUNNER,
+        runner,
+        HistoryEvent::STATUS,
+        Some(status.as_ref()),
+        Some(runner),
+    )
+    .await;
+    Ok(())
+}
+
+pub async fn get_runner_status(db: &mut SqliteConnection, runner: &str) -> RunnerStatus {
+    let status = sqlx::query_as::<_, (String,)>("SELECT Status FROM RunnerVMs WHERE Id = ?")
+        .bind(runner)
+        .fetch_one(db)
+        .await
+        .unwrap()
+        .0;
+    RunnerStatus::from_str(&status).expect("Invalid Runner Status: Database Corruption")
 }
 
 pub async fn update_runner_time_to_reset(
@@ -73,11 +217,324 @@ pub async fn update_runner_time_to_reset(
     sqlx::query("UPDATE RunnerVMs SET TimeToReset = ? WHERE Id = ?")
         .bind(time_to_reset)
         .bind(runner)
+        .execute(&mut *db)
+        .await
+        .unwrap();
+
+    let value = time_to_reset.map(|t| timestamp::Timestamp::from(t).to_string());
+    insert_history(
+        db,
+        HistoryKind::RUNNER,
+        runner,
+        HistoryEvent::TIME_TO_RESET,
+        value.as_deref(),
+        Some(runner),
+    )
+    .await;
+}
+
+pub async fn get_runner_backend(db: &mut SqliteConnection, runner: &str) -> Option<vm::BackendKind> {
+    sqlx::query_as::<_, (Option<String>,)>("SELECT Backend FROM RunnerVMs WHERE Id = ?")
+        .bind(runner)
+        .fetch_one(db)
+        .await
+        .unwrap()
+        .0
+        .map(|backend| {
+            vm::BackendKind::from_str(&backend).expect("Invalid Backend: Database Corruption")
+        })
+}
+
+pub async fn update_runner_backend(
+    db: &mut SqliteConnection,
+    runner: &str,
+    backend: Option<vm::BackendKind>,
+) {
+    sqlx::query("UPDATE RunnerVMs SET Backend = ? WHERE Id = ?")
+        .bind(backend.as_ref().map(|backend| backend.as_ref()))
+        .bind(runner)
+        .execute(db)
+        .await
+        .unwrap();
+}
+
+/// NULL for the one selected via GITHUB_ORG or GITHUB_OWNER/GITHUB_REPO
+pub async fn get_runner_scope(db: &mut SqliteConnection, runner: &str) -> Option<github::Scope> {
+    sqlx::query_as::<_, (Option<String>,)>("SELECT Scope FROM RunnerVMs WHERE Id = ?")
+        .bind(runner)
+        .fetch_one(db)
+        .await
+        .unwrap()
+        .0
+        .map(|scope| github::Scope::parse(&scope).expect("Invalid Scope: Database Corruption"))
+}
+
+pub async fn update_runner_scope(
+    db: &mut SqliteConnection,
+    runner: &str,
+    scope: Option<&github::Scope>,
+) {
+    sqlx::query("UPDATE RunnerVMs SET Scope = ? WHERE Id = ?")
+        .bind(scope.map(|scope| scope.to_string()))
+        .bind(runner)
+        .execute(db)
+        .await
+        .unwrap();
+}
+
+/// Stored comma separated, NULL for the ones selected via RUNNER_LABELS
+pub async fn get_runner_labels(db: &mut SqliteConnection, runner: &str) -> Option<Vec<String>> {
+    sqlx::query_as::<_, (Option<String>,)>("SELECT Labels FROM RunnerVMs WHERE Id = ?")
+        .bind(runner)
+        .fetch_one(db)
+        .await
+        .unwrap()
+        .0
+        .map(|labels| labels.split(',').map(str::to_string).collect())
+}
+
+pub async fn update_runner_labels(
+    db: &mut SqliteConnection,
+    runner: &str,
+    labels: Option<&[String]>,
+) {
+    sqlx::query("UPDATE RunnerVMs SET Labels = ? WHERE Id = ?")
+        .bind(labels.map(|labels| labels.join(",")))
+        .bind(runner)
+        .execute(db)
+        .await
+        .unwrap();
+}
+
+/// Whether the runner registered with a just-in-time config instead of a token
+pub async fn get_runner_jit(db: &mut SqliteConnection, runner: &str) -> bool {
+    sqlx::query_as::<_, (bool,)>("SELECT Jit FROM RunnerVMs WHERE Id = ?")
+        .bind(runner)
+        .fetch_one(db)
+        .await
+        .unwrap()
+        .0
+}
+
+pub async fn update_runner_jit(db: &mut SqliteConnection, runner: &str, jit: bool) {
+    sqlx::query("UPDATE RunnerVMs SET Jit = ? WHERE Id = ?")
+        .bind(jit)
+        .bind(runner)
+        .execute(db)
+        .await
+        .unwrap();
+}
+
+pub async fn get_runner_group(db: &mut SqliteConnection, runner: &str) -> Option<i64> {
+    sqlx::query_as::<_, (Option<i64>,)>("SELECT RunnerGroup FROM RunnerVMs WHERE Id = ?")
+        .bind(runner)
+        .fetch_one(db)
+        .await
+        .unwrap()
+        .0
+}
+
+pub async fn update_runner_group(db: &mut SqliteConnection, runner: &str, group: Option<i64>) {
+    sqlx::query("UPDATE RunnerVMs SET RunnerGroup = ? WHERE Id = ?")
+        .bind(group)
+        .bind(runner)
+        .execute(db)
+        .await
+        .unwrap();
+}
+
+pub async fn update_runner_last_command(
+    db: &mut SqliteConnection,
+    runner: &str,
+    command: vm::Command,
+    status: CommandStatus,
+    message: Option<&str>,
+) {
+    sqlx::query(
+        "UPDATE RunnerVMs SET LastCommand = ?, LastCommandStatus = ?, LastCommandMessage = ?, \
+         LastCommandTime = ? WHERE Id = ?",
+    )
+    .bind(command.as_ref())
+    .bind(status.as_ref())
+    .bind(message)
+    .bind(chrono::Utc::now().naive_utc())
+    .bind(runner)
+    .execute(db)
+    .await
+    .unwrap();
+}
+
+pub async fn get_runner_last_command(
+    db: &mut SqliteConnection,
+    runner: &str,
+) -> Option<vm::CommandOutcome> {
+    let data = sqlx::query_as::<
+        _,
+        (Option<String>, Option<String>, Option<String>, Option<chrono::NaiveDateTime>),
+    >(
+        "SELECT LastCommand, LastCommandStatus, LastCommandMessage, LastCommandTime \
+         FROM RunnerVMs WHERE Id = ?",
+    )
+    .bind(runner)
+    .fetch_one(db)
+    .await
+    .unwrap();
+
+    let (Some(command), Some(status), message, Some(time)) = data else {
+        return None;
+    };
+    Some(vm::CommandOutcome {
+        command,
+        status: CommandStatus::from_str(&status).expect("Invalid Command Status: Database Corruption"),
+        message,
+        time: timestamp::Timestamp::from(time),
+    })
+}
+
+pub async fn update_runner_power_state(
+    db: &mut SqliteConnection,
+    runner: &str,
+    state: &vm::PowerState,
+) {
+    sqlx::query("UPDATE RunnerVMs SET PowerState = ?, PowerStateTime = ? WHERE Id = ?")
+        .bind(state.as_ref())
+        .bind(chrono::Utc::now().naive_utc())
+        .bind(runner)
+        .execute(db)
+        .await
+        .unwrap();
+}
+
+pub async fn get_runner_power_state(
+    db: &mut SqliteConnection,
+    runner: &str,
+) -> Option<vm::PowerState> {
+    sqlx::query_as::<_, (Option<String>,)>("SELECT PowerState FROM RunnerVMs WHERE Id = ?")
+        .bind(runner)
+        .fetch_one(db)
+        .await
+        .unwrap()
+        .0
+        .map(|state| {
+            vm::PowerState::from_str(&state).expect("Invalid Power State: Database Corruption")
+        })
+}
+
+pub async fn get_runner_current_snapshot(db: &mut SqliteConnection, runner: &str) -> Option<String> {
+    sqlx::query_as::<_, (Option<String>,)>("SELECT CurrentSnapshot FROM RunnerVMs WHERE Id = ?")
+        .bind(runner)
+        .fetch_one(db)
+        .await
+        .unwrap()
+        .0
+}
+
+pub async fn update_runner_current_snapshot(
+    db: &mut SqliteConnection,
+    runner: &str,
+    snapshot: Option<&str>,
+) {
+    sqlx::query("UPDATE RunnerVMs SET CurrentSnapshot = ? WHERE Id = ?")
+        .bind(snapshot)
+        .bind(runner)
         .execute(db)
         .await
         .unwrap();
 }
 
+pub async fn get_runner_last_snapshot(
+    db: &mut SqliteConnection,
+    runner: &str,
+) -> Option<chrono::NaiveDateTime> {
+    sqlx::query_as::<_, (Option<chrono::NaiveDateTime>,)>(
+        "SELECT LastSnapshot FROM RunnerVMs WHERE Id = ?",
+    )
+    .bind(runner)
+    .fetch_one(db)
+    .await
+    .unwrap()
+    .0
+}
+
+pub async fn update_runner_last_snapshot(
+    db: &mut SqliteConnection,
+    runner: &str,
+    time: chrono::NaiveDateTime,
+) {
+    sqlx::query("UPDATE RunnerVMs SET LastSnapshot = ? WHERE Id = ?")
+        .bind(time)
+        .bind(runner)
+        .execute(db)
+        .await
+        .unwrap();
+}
+
+pub async fn get_runner_last_active(
+    db: &mut SqliteConnection,
+    runner: &str,
+) -> Option<chrono::NaiveDateTime> {
+    sqlx::query_as::<_, (Option<chrono::NaiveDateTime>,)>(
+        "SELECT LastActive FROM RunnerVMs WHERE Id = ?",
+    )
+    .bind(runner)
+    .fetch_one(db)
+    .await
+    .unwrap()
+    .0
+}
+
+pub async fn update_runner_last_active(db: &mut SqliteConnection, runner: &str) {
+    sqlx::query("UPDATE RunnerVMs SET LastActive = ? WHERE Id = ?")
+        .bind(chrono::Utc::now().naive_utc())
+        .bind(runner)
+        .execute(db)
+        .await
+        .unwrap();
+}
+
+pub async fn update_runner_job(
+    db: &mut SqliteConnection,
+    runner: &str,
+    job: Option<&runners::RunnerJob>,
+) {
+    sqlx::query(
+        "UPDATE RunnerVMs SET JobId = ?, JobRunId = ?, JobName = ?, JobRepository = ?, JobUrl = ? \
+         WHERE Id = ?",
+    )
+    .bind(job.map(|job| job.id))
+    .bind(job.map(|job| job.run_id))
+    .bind(job.map(|job| job.name.as_str()))
+    .bind(job.map(|job| job.repository.as_str()))
+    .bind(job.and_then(|job| job.url.as_deref()))
+    .bind(runner)
+    .execute(db)
+    .await
+    .unwrap();
+}
+
+pub async fn get_runner_job(db: &mut SqliteConnection, runner: &str) -> Option<runners::RunnerJob> {
+    let data = sqlx::query_as::<_, JobRecord>(
+        "SELECT JobId, JobRunId, JobName, JobRepository, JobUrl FROM RunnerVMs WHERE Id = ?",
+    )
+    .bind(runner)
+    .fetch_one(db)
+    .await
+    .unwrap();
+
+    let (Some(id), Some(run_id), Some(name), Some(repository)) =
+        (data.JobId, data.JobRunId, data.JobName, data.JobRepository)
+    else {
+        return None;
+    };
+    Some(runners::RunnerJob {
+        id,
+        run_id,
+        name,
+        repository,
+        url: data.JobUrl,
+    })
+}
+
 pub async fn runner_id_list(db: &mut SqliteConnection) -> Vec<String> {
     sqlx::query!("SELECT Id FROM RunnerVMs")
         .fetch_all(&mut *db)
@@ -93,7 +550,7 @@ pub async fn get_runner_info(db: &mut SqliteConnection, runner: &str) -> runners
         "SELECT Id, Status, TimeToReset FROM RunnerVMs WHERE Id = ?",
         runner
     )
-    .fetch_one(db)
+    .fetch_one(&mut *db)
     .await
     .unwrap();
 
@@ -104,7 +561,38 @@ pub async fn get_runner_info(db: &mut SqliteConnection, runner: &str) -> runners
     } else {
         None
     };
-    runners::RunnerInfo::new(data.Id, runner_status, timestamp)
+
+    let mut claimed_hardware = Vec::new();
+    for hardware in get_hardware_claimed_by_runner(db, runner).await {
+        claimed_hardware.push(get_hardware_info(db, &hardware).await);
+    }
+    let backend = get_runner_backend(db, runner).await.unwrap_or(vm::default_backend());
+    let scope = get_runner_scope(db, runner).await.or(github::default_scope());
+    let labels = get_runner_labels(db, runner).await.unwrap_or(github::default_labels());
+    let runner_group = get_runner_group(db, runner).await.unwrap_or(*github::RUNNER_GROUP);
+    let last_command = get_runner_last_command(db, runner).await;
+    let power_state = get_runner_power_state(db, runner).await;
+    let last_snapshot = get_runner_last_snapshot(db, runner).await.map(timestamp::Timestamp::from);
+    let last_active = get_runner_last_active(db, runner).await.map(timestamp::Timestamp::from);
+    let job = get_runner_job(db, runner).await;
+    let idle_reset_at =
+        runners::RunnerInfo::idle_reset_at(&runner_status, &timestamp, &last_active);
+    runners::RunnerInfo {
+        name: data.Id,
+        status: runner_status,
+        time_to_reset: timestamp,
+        claimed_hardware,
+        backend,
+        scope: scope.map(|scope| scope.to_string()),
+        labels,
+        runner_group,
+        last_command,
+        power_state,
+        last_snapshot,
+        last_active,
+        idle_reset_at,
+        job,
+    }
 }
 
 //------------------------------------------------------------------------------
@@ -120,6 +608,23 @@ pub async fn hardware_exists(db: &mut SqliteConnection, hardware: &str) -> bool
         .0
 }
 
+pub async fn insert_hardware(db: &mut SqliteConnection, hardware: &str, status: HardwareStatus) {
+    sqlx::query("INSERT INTO Hardware (Id, Status, ClaimedBy) VALUES (?, ?, NULL)")
+        .bind(hardware)
+        .bind(status.as_ref())
+        .execute(db)
+        .await
+        .unwrap();
+}
+
+pub async fn delete_hardware(db: &mut SqliteConnection, hardware: &str) {
+    sqlx::query("DELETE FROM Hardware WHERE Id = ?")
+        .bind(hardware)
+        .execute(db)
+        .await
+        .unwrap();
+}
+
 pub async fn get_hardware_status(db: &mut SqliteConnection, hardware: &str) -> HardwareStatus {
     let a = sqlx::query!("SELECT Status FROM Hardware WHERE Id = ?", hardware)
         .fetch_one(db)
@@ -145,9 +650,15 @@ pub async fn get_hardware_claimed_by_runner(
 pub async fn update_hardware_status(
     db: &mut SqliteConnection,
     hardware: &str,
-    runner: &str,
+    runner: Option<&str>,
     status: HardwareStatus,
 ) -> Result<()> {
+    let current = get_hardware_status(db, hardware).await;
+    transition::check_hardware(hardware, &current, &status)?;
+
+    // Releases clear ClaimedBy, the history still records who held the board
+    let previous = get_hardware_info(db, hardware).await.claimed_by;
+
     let status_str = status.as_ref().to_owned();
     sqlx::query!(
         "UPDATE Hardware SET Status = ?, ClaimedBy = ? WHERE Id = ?",
@@ -155,9 +666,20 @@ pub async fn update_hardware_status(
         runner,
         hardware
     )
-    .execute(db)
+    .execute(&mut *db)
     .await
     .unwrap();
+
+    let runner = runner.or(previous.as_deref());
+    insert_history(
+        db,
+        HistoryKind::HARDWARE,
+        hardware,
+        HistoryEvent::STATUS,
+        Some(status.as_ref()),
+        runner,
+    )
+    .await;
     Ok(())
 }
 
@@ -165,17 +687,90 @@ pub async fn get_hardware_info(
     db: &mut SqliteConnection,
     hardware: &str,
 ) -> hardware::HardwareInfo {
-    let data = sqlx::query!(
-        "SELECT Id, Status, ClaimedBy FROM Hardware WHERE Id = ?",
-        hardware
+    let data = sqlx::query_as::<_, HardwareRecord>(
+        "SELECT Id, Status, ClaimedBy, Type, LeaseExpiry, \
+         ClaimRepository, ClaimRunId, ClaimJob, ClaimSha FROM Hardware WHERE Id = ?",
     )
+    .bind(hardware)
     .fetch_one(db)
     .await
     .unwrap();
 
     let hw_status = HardwareStatus::from_str(&data.Status)
         .expect("Invalid Hardware Status: Database Corruption");
-    hardware::HardwareInfo::new(data.Id, hw_status, data.ClaimedBy)
+    let lease_expiry = data.LeaseExpiry.map(timestamp::Timestamp::from);
+    let claim = data.ClaimedBy.as_ref().map(|_| hardware::ClaimMetadata {
+        repository: data.ClaimRepository,
+        run_id: data.ClaimRunId,
+        job: data.ClaimJob,
+        sha: data.ClaimSha,
+    });
+    hardware::HardwareInfo::new(data.Id, hw_status, data.ClaimedBy, data.Type, lease_expiry, claim)
+}
+
+pub async fn update_hardware_claim_metadata(
+    db: &mut SqliteConnection,
+    hardware: &str,
+    metadata: &hardware::ClaimMetadata,
+) {
+    sqlx::query(
+        "UPDATE Hardware SET ClaimRepository = ?, ClaimRunId = ?, ClaimJob = ?, ClaimSha = ? \
+         WHERE Id = ?",
+    )
+    .bind(&metadata.repository)
+    .bind(metadata.run_id)
+    .bind(&metadata.job)
+    .bind(&metadata.sha)
+    .bind(hardware)
+    .execute(db)
+    .await
+    .unwrap();
+}
+
+pub async fn update_hardware_lease(
+    db: &mut SqliteConnection,
+    hardware: &str,
+    lease_expiry: Option<chrono::NaiveDateTime>,
+) {
+    sqlx::query("UPDATE Hardware SET LeaseExpiry = ? WHERE Id = ?")
+        .bind(lease_expiry)
+        .bind(hardware)
+        .execute(db)
+        .await
+        .unwrap();
+}
+
+/// Returns (board, runner) of all claims whose lease lapsed
+pub async fn get_hardware_with_expired_lease(db: &mut SqliteConnection) -> Vec<(String, String)> {
+    sqlx::query_as::<_, (String, String)>(
+        "SELECT Id, ClaimedBy FROM Hardware \
+         WHERE Status = 'CLAIMED' AND ClaimedBy IS NOT NULL AND LeaseExpiry < ?",
+    )
+    .bind(chrono::Utc::now().naive_utc())
+    .fetch_all(db)
+    .await
+    .unwrap()
+}
+
+pub async fn update_hardware_type(
+    db: &mut SqliteConnection,
+    hardware: &str,
+    board_type: Option<&str>,
+) {
+    if let Some(board_type) = board_type {
+        sqlx::query("INSERT OR IGNORE INTO BoardTypes (Id) VALUES (?)")
+            .bind(board_type)
+            .execute(&mut *db)
+            .await
+            .unwrap();
+    }
+
+    sqlx::query("UPDATE Hardware SET Type = ? WHERE Id = ?")
+        .bind(board_type)
+        .bind(hardware)
+        .execute(db)
+        .await
+        .unwrap();
 }
 
 pub async fn hardware_board_list(db: &mut SqliteConnection) -> Vec<String> {
@@ -187,3 +782,424 @@ pub async fn hardware_board_list(db: &mut SqliteConnection) -> Vec<String> {
         .map(|rec| rec.Id)
         .collect()
 }
+
+//------------------------------------------------------------------------------
+// Board Types
+//------------------------------------------------------------------------------
+
+pub async fn board_type_exists(db: &mut SqliteConnection, board_type: &str) -> bool {
+    1 == sqlx::query_as::<_, (i64,)>("SELECT EXISTS (SELECT 1 FROM BoardTypes WHERE Id = ?)")
+        .bind(board_type)
+        .fetch_one(db)
+        .await
+        .unwrap()
+        .0
+}
+
+pub async fn hardware_of_type_list(db: &mut SqliteConnection, board_type: &str) -> Vec<String> {
+    sqlx::query_as::<_, (String,)>("SELECT Id FROM Hardware WHERE Type = ?")
+        .bind(board_type)
+        .fetch_all(db)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|rec| rec.0)
+        .collect()
+}
+
+pub async fn get_free_hardware_of_type(
+    db: &mut SqliteConnection,
+    board_type: &str,
+) -> Option<String> {
+    sqlx::query_as::<_, (String,)>(
+        "SELECT Id FROM Hardware WHERE Type = ? AND Status = 'FREE' \
+         AND NOT EXISTS (SELECT 1 FROM HardwareQueue WHERE HardwareQueue.Hardware = Hardware.Id) \
+         ORDER BY Id LIMIT 1",
+    )
+    .bind(board_type)
+    .fetch_optional(db)
+    .await
+    .unwrap()
+    .map(|rec| rec.0)
+}
+
+//------------------------------------------------------------------------------
+// Hardware Queue
+//------------------------------------------------------------------------------
+
+pub async fn enqueue_claim(
+    db: &mut SqliteConnection,
+    hardware: &str,
+    runner: &str,
+    metadata: &hardware::ClaimMetadata,
+) {
+    let result = sqlx::query(
+        "INSERT OR IGNORE INTO HardwareQueue \
+         (Hardware, Runner, EnqueuedAt, ClaimRepository, ClaimRunId, ClaimJob, ClaimSha) \
+         VALUES (?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(hardware)
+    .bind(runner)
+    .bind(chrono::Utc::now().naive_utc())
+    .bind(&metadata.repository)
+    .bind(metadata.run_id)
+    .bind(&metadata.job)
+    .bind(&metadata.sha)
+    .execute(&mut *db)
+    .await
+    .unwrap();
+
+    if result.rows_affected() > 0 {
+        insert_history(
+            db,
+            HistoryKind::HARDWARE,
+            hardware,
+            HistoryEvent::QUEUED,
+            None,
+            Some(runner),
+        )
+        .await;
+    }
+}
+
+pub async fn dequeue_claim(db: &mut SqliteConnection, hardware: &str, runner: &str) -> bool {
+    sqlx::query("DELETE FROM HardwareQueue WHERE Hardware = ? AND Runner = ?")
+        .bind(hardware)
+        .bind(runner)
+        .execute(db)
+        .await
+        .unwrap()
+        .rows_affected()
+        > 0
+}
+
+pub async fn dequeue_runner(db: &mut SqliteConnection, runner: &str) {
+    sqlx::query("DELETE FROM HardwareQueue WHERE Runner = ?")
+        .bind(runner)
+        .execute(db)
+        .await
+        .unwrap();
+}
+
+pub async fn queue_head(db: &mut SqliteConnection, hardware: &str) -> Option<String> {
+    sqlx::query_as::<_, (String,)>(
+        "SELECT Runner FROM HardwareQueue WHERE Hardware = ? ORDER BY Id LIMIT 1",
+    )
+    .bind(hardware)
+    .fetch_optional(db)
+    .await
+    .unwrap()
+    .map(|rec| rec.0)
+}
+
+pub async fn get_queue_metadata(
+    db: &mut SqliteConnection,
+    hardware: &str,
+    runner: &str,
+) -> hardware::ClaimMetadata {
+    let (repository, run_id, job, sha) = sqlx::query_as::<
+        _,
+        (Option<String>, Option<i64>, Option<String>, Option<String>),
+    >(
+        "SELECT ClaimRepository, ClaimRunId, ClaimJob, ClaimSha FROM HardwareQueue \
+         WHERE Hardware = ? AND Runner = ?",
+    )
+    .bind(hardware)
+    .bind(runner)
+    .fetch_optional(db)
+    .await
+    .unwrap()
+    .unwrap_or_default();
+
+    hardware::ClaimMetadata {
+        repository,
+        run_id,
+        job,
+        sha,
+    }
+}
+
+/// 1-based position of the runner in the queue of the board, `None` if not queued
+pub async fn queue_position(
+    db: &mut SqliteConnection,
+    hardware: &str,
+    runner: &str,
+) -> Option<i64> {
+    sqlx::query_as::<_, (Option<i64>,)>(
+        "SELECT (SELECT COUNT(*) FROM HardwareQueue WHERE Hardware = q.Hardware AND Id <= q.Id) \
+         FROM HardwareQueue q WHERE q.Hardware = ? AND q.Runner = ?",
+    )
+    .bind(hardware)
+    .bind(runner)
+    .fetch_optional(db)
+    .await
+    .unwrap()
+    .and_then(|rec| rec.0)
+}
+
+pub async fn get_queue(
+    db: &mut SqliteConnection,
+    hardware: &str,
+) -> Vec<(String, chrono::NaiveDateTime)> {
+    sqlx::query_as::<_, (String, chrono::NaiveDateTime)>(
+        "SELECT Runner, EnqueuedAt FROM HardwareQueue WHERE Hardware = ? ORDER BY Id",
+    )
+    .bind(hardware)
+    .fetch_all(db)
+    .await
+    .unwrap()
+}
+
+//------------------------------------------------------------------------------
+// History
+//------------------------------------------------------------------------------
+
+pub async fn insert_history(
+    db: &mut SqliteConnection,
+    kind: HistoryKind,
+    subject: &str,
+    event: HistoryEvent,
+    value: Option<&str>,
+    runner: Option<&str>,
+) {
+    sqlx::query(
+        "INSERT INTO History (Time, Kind, Subject, Event, Value, Runner) VALUES (?, ?, ?, ?, ?, ?)",
+    )
+    .bind(chrono::Utc::now().naive_utc())
+    .bind(kind.as_ref())
+    .bind(subject)
+    .bind(event.as_ref())
+    .bind(value)
+    .bind(runner)
+    .execute(db)
+    .await
+    .unwrap();
+}
+
+pub async fn get_history(
+    db: &mut SqliteConnection,
+    kind: HistoryKind,
+    subject: &str,
+    from: Option<chrono::NaiveDateTime>,
+    to: Option<chrono::NaiveDateTime>,
+) -> Vec<HistoryRecord> {
+    sqlx::query_as::<_, HistoryRecord>(
+        "SELECT Time, Subject, Event, Value, Runner FROM History \
+         WHERE Kind = ? AND Subject = ? AND (? IS NULL OR Time >= ?) AND (? IS NULL OR Time <= ?) \
+         ORDER BY Id",
+    )
+    .bind(kind.as_ref())
+    .bind(subject)
+    .bind(from)
+    .bind(from)
+    .bind(to)
+    .bind(to)
+    .fetch_all(db)
+    .await
+    .unwrap()
+}
+
+/// History of one kind within [from, to] in the order it was recorded, preceded by what is
+/// still in effect at `from`: the last status of each subject and queue entries not granted
+pub async fn get_history_window(
+    db: &mut SqliteConnection,
+    kind: HistoryKind,
+    from: chrono::NaiveDateTime,
+    to: chrono::NaiveDateTime,
+) -> Vec<HistoryRecord> {
+    sqlx::query_as::<_, HistoryRecord>(
+        "SELECT Time, Subject, Event, Value, Runner FROM History AS H WHERE Kind = ?1 AND ( \
+             (Time >= ?2 AND Time <= ?3) \
+             OR Id IN (SELECT MAX(Id) FROM History \
+                 WHERE Kind = ?1 AND Event = 'STATUS' AND Time < ?2 GROUP BY Subject) \
+             OR (Event = 'QUEUED' AND Time < ?2 AND NOT EXISTS (SELECT 1 FROM History AS C \
+                 WHERE C.Kind = ?1 AND C.Subject = H.Subject AND C.Runner = H.Runner \
+                 AND C.Event = 'STATUS' AND C.Value = 'CLAIMED' AND C.Id > H.Id AND C.Time < ?2))) \
+         ORDER BY Id",
+    )
+    .bind(kind.as_ref())
+    .bind(from)
+    .bind(to)
+    .fetch_all(db)
+    .await
+    .unwrap()
+}
+
+//------------------------------------------------------------------------------
+// VM Command Queue
+//------------------------------------------------------------------------------
+
+pub async fn insert_vm_command(
+    db: &mut SqliteConnection,
+    runner: &str,
+    command: vm::Command,
+    argument: Option<&str>,
+    not_before: chrono::NaiveDateTime,
+) {
+    let now = chrono::Utc::now().naive_utc();
+    sqlx::query(
+        "INSERT INTO VmCommands \
+         (Runner, Command, Argument, Status, Attempts, NotBefore, CreatedAt, UpdatedAt) \
+         VALUES (?, ?, ?, ?, 0, ?, ?, ?)",
+    )
+    .bind(runner)
+    .bind(command.as_ref())
+    .bind(argument)
+    .bind(VmCommandStatus::PENDING.as_ref())
+    .bind(not_before)
+    .bind(now)
+    .bind(now)
+    .execute(db)
+    .await
+    .unwrap();
+}
+
+pub async fn update_vm_command(
+    db: &mut SqliteConnection,
+    id: i64,
+    status: VmCommandStatus,
+    attempts: i64,
+    not_before: chrono::NaiveDateTime,
+    last_error: Option<&str>,
+) {
+    sqlx::query(
+        "UPDATE VmCommands SET Status = ?, Attempts = ?, NotBefore = ?, LastError = ?, UpdatedAt = ? \
+         WHERE Id = ?",
+    )
+    .bind(status.as_ref())
+    .bind(attempts)
+    .bind(not_before)
+    .bind(last_error)
+    .bind(chrono::Utc::now().naive_utc())
+    .bind(id)
+    .execute(db)
+    .await
+    .unwrap();
+}
+
+pub async fn get_vm_commands(db: &mut SqliteConnection, runner: &str) -> Vec<VmCommandRecord> {
+    sqlx::query_as::<_, VmCommandRecord>("SELECT * FROM VmCommands WHERE Runner = ? ORDER BY Id")
+        .bind(runner)
+        .fetch_all(db)
+        .await
+        .unwrap()
+}
+
+pub async fn has_unfinished_vm_command(db: &mut SqliteConnection, runner: &str) -> bool {
+    sqlx::query_as::<_, (i64,)>(
+        "SELECT COUNT(*) FROM VmCommands WHERE Runner = ? AND Status IN ('PENDING', 'SENT')",
+    )
+    .bind(runner)
+    .fetch_one(db)
+    .await
+    .unwrap()
+    .0
+        > 0
+}
+
+/// Commands which finished before the given time
+pub async fn delete_finished_vm_commands(
+    db: &mut SqliteConnection,
+    before: chrono::NaiveDateTime,
+) {
+    sqlx::query(
+        "DELETE FROM VmCommands WHERE Status IN ('ACKNOWLEDGED', 'FAILED') AND UpdatedAt < ?",
+    )
+    .bind(before)
+    .execute(db)
+    .await
+    .unwrap();
+}
+
+/// Oldest unfinished command of every runner, commands of a runner are run in order
+pub async fn next_vm_commands(db: &mut SqliteConnection) -> Vec<VmCommandRecord> {
+    sqlx::query_as::<_, VmCommandRecord>(
+        "SELECT * FROM VmCommands WHERE Id IN \
+         (SELECT MIN(Id) FROM VmCommands WHERE Status IN ('PENDING', 'SENT') GROUP BY Runner) \
+         ORDER BY Id",
+    )
+    .fetch_all(db)
+    .await
+    .unwrap()
+}
+
+//------------------------------------------------------------------------------
+// Snapshots
+//------------------------------------------------------------------------------
+
+pub async fn insert_snapshot(
+    db: &mut SqliteConnection,
+    runner: &str,
+    snapshot: &str,
+    base_image: Option<&str>,
+) {
+    sqlx::query(
+        "INSERT INTO Snapshots (Runner, Name, BaseImage, CreatedAt, Available) VALUES (?, ?, ?, ?, 0)",
+    )
+    .bind(runner)
+    .bind(snapshot)
+    .bind(base_image)
+    .bind(chrono::Utc::now().naive_utc())
+    .execute(db)
+    .await
+    .unwrap();
+}
+
+pub async fn update_snapshot_available(db: &mut SqliteConnection, runner: &str, snapshot: &str) {
+    sqlx::query("UPDATE Snapshots SET Available = 1 WHERE Runner = ? AND Name = ?")
+        .bind(runner)
+        .bind(snapshot)
+        .execute(db)
+        .await
+        .unwrap();
+}
+
+pub async fn delete_snapshot(db: &mut SqliteConnection, runner: &str, snapshot: &str) {
+    sqlx::query("DELETE FROM Snapshots WHERE Runner = ? AND Name = ?")
+        .bind(runner)
+        .bind(snapshot)
+        .execute(db)
+        .await
+        .unwrap();
+}
+
+pub async fn get_snapshot(
+    db: &mut SqliteConnection,
+    runner: &str,
+    snapshot: &str,
+) -> Option<SnapshotRecord> {
+    sqlx::query_as::<_, SnapshotRecord>(
+        "SELECT Name, BaseImage, CreatedAt, Available FROM Snapshots WHERE Runner = ? AND Name = ?",
+    )
+    .bind(runner)
+    .bind(snapshot)
+    .fetch_optional(db)
+    .await
+    .unwrap()
+}
+
+/// Snapshots of a runner, newest first
+pub async fn get_snapshots(db: &mut SqliteConnection, runner: &str) -> Vec<SnapshotRecord> {
+    sqlx::query_as::<_, SnapshotRecord>(
+        "SELECT Name, BaseImage, CreatedAt, Available FROM Snapshots WHERE Runner = ? \
+         ORDER BY Id DESC",
+    )
+    .bind(runner)
+    .fetch_all(db)
+    .await
+    .unwrap()
+}
+
+//------------------------------------------------------------------------------
+// Tests
+//------------------------------------------------------------------------------
+
+/// Empty in-memory database with all migrations applied
+#[cfg(test)]
+pub async fn test_db() -> SqliteConnection {
+    use sqlx::Connection;
+
+    let mut db = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+    sqlx::migrate!("./migrations").run(&mut db).await.unwrap();
+    db
+}
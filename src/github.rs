@@ -0,0 +1,376 @@
+//
+// Copyright (C) 2024, HENSOLDT Cyber GmbH
+// 
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// For commercial licensing, contact: info.cyber@hensoldt.net
+//
+
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use reqwest::{header, Client, Proxy, RequestBuilder, Response};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::tokio::sync::Mutex;
+use std::collections::HashMap;
+use std::time::Duration;
+use std::{env, fmt, fs, sync::LazyLock};
+
+
+
+//------------------------------------------------------------------------------
+// Config
+//------------------------------------------------------------------------------
+
+
+/// Base URL of the REST API, can point to a mock server for testing
+static API_URL: LazyLock<String> = LazyLock::new(|| {
+    dotenv::dotenv().ok();
+    let url = env::var("GITHUB_API_URL").unwrap_or("https://api.github.com".to_string());
+    url.trim_end_matches('/').to_string()
+});
+
+/// GitHub App the API authenticates as, takes precedence over `GITHUB_PAT`
+static APP: LazyLock<Option<AppCredentials>> = LazyLock::new(|| {
+    dotenv::dotenv().ok();
+    Some(AppCredentials {
+        app_id: env::var("GITHUB_APP_ID").ok()?,
+        private_key: env::var("GITHUB_APP_PRIVATE_KEY").ok()?,
+    })
+});
+
+/// Web URL runners register at, e.g. the one of a GitHub Enterprise Server
+static WEB_URL: LazyLock<String> = LazyLock::new(|| {
+    dotenv::dotenv().ok();
+    let url = env::var("GITHUB_URL").unwrap_or("https://github.com".to_string());
+    url.trim_end_matches('/').to_string()
+});
+
+/// Scope of runners without their own, `GITHUB_ORG` or else `GITHUB_OWNER`/`GITHUB_REPO`
+static DEFAULT_SCOPE: LazyLock<Option<Scope>> = LazyLock::new(|| {
+    dotenv::dotenv().ok();
+    if let Ok(org) = env::var("GITHUB_ORG") {
+        return Scope::parse(&org);
+    }
+    let owner = env::var("GITHUB_OWNER").ok()?;
+    let repo = env::var("GITHUB_REPO").ok()?;
+    Scope::parse(&format!("{}/{}", owner, repo))
+});
+
+/// Requests are given up after this time, GitHub calls block whoever waits for them
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Labels of runners without their own, comma separated
+static RUNNER_LABELS: LazyLock<Vec<String>> = LazyLock::new(|| {
+    dotenv::dotenv().ok();
+    let labels = env::var("RUNNER_LABELS").unwrap_or("self-hosted".to_string());
+    labels.split(',').map(|label| label.trim().to_string()).collect()
+});
+
+/// Runner group of runners without their own, 1 is the default group of an organization
+pub static RUNNER_GROUP: LazyLock<i64> = LazyLock::new(|| {
+    dotenv::dotenv().ok();
+    env::var("RUNNER_GROUP_ID").ok().and_then(|id| id.parse().ok()).unwrap_or(1)
+});
+
+/// Installation tokens are valid for an hour, they are reused until shortly before. Keyed by
+/// the account the app is installed on.
+static INSTALLATION_TOKENS: LazyLock<Mutex<HashMap<String, InstallationToken>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+
+
+//------------------------------------------------------------------------------
+// Data Structures
+//------------------------------------------------------------------------------
+
+
+/// Where runners are registered, written as `<org>` or `<owner>/<repo>`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Scope {
+    Organization(String),
+    Repository(String, String),
+}
+
+impl Scope {
+    pub fn parse(scope: &str) -> Option<Self> {
+        let allowed = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.';
+        let valid = |part: &str| !part.is_empty() && part.chars().all(allowed);
+
+        match scope.split_once('/') {
+            None if valid(scope) => Some(Self::Organization(scope.to_string())),
+            Some((owner, repo)) if valid(owner) && valid(repo) => {
+                Some(Self::Repository(owner.to_string(), repo.to_string()))
+            }
+            _ => None,
+        }
+    }
+
+    /// What `config.sh --url` expects for the scope
+    pub fn url(&self) -> String {
+        format!("{}/{}", *WEB_URL, self)
+    }
+
+    /// Prefix of the REST API paths of the scope
+    fn path(&self) -> String {
+        match self {
+            Self::Organization(org) => format!("orgs/{}", org),
+            Self::Repository(owner, repo) => format!("repos/{}/{}", owner, repo),
+        }
+    }
+
+    /// Account the credentials have to be valid for
+    fn owner(&self) -> &str {
+        match self {
+            Self::Organization(org) => org,
+            Self::Repository(owner, _) => owner,
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Organization(org) => f.write_str(org),
+            Self::Repository(owner, repo) => write!(f, "{}/{}", owner, repo),
+        }
+    }
+}
+
+
+struct AppCredentials {
+    app_id: String,
+    /// Path to the PEM encoded private key of the app
+    private_key: String,
+}
+
+
+#[derive(Serialize)]
+struct Claims {
+    iat: i64,
+    exp: i64,
+    iss: String,
+}
+
+
+#[derive(Clone, Deserialize)]
+struct InstallationToken {
+    token: String,
+    expires_at: DateTime<Utc>,
+}
+
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    token: String,
+}
+
+
+#[derive(Serialize)]
+struct JitConfigRequest<'a> {
+    name: &'a str,
+    runner_group_id: i64,
+    labels: &'a [String],
+    work_folder: &'a str,
+}
+
+
+#[derive(Deserialize)]
+struct JitConfigResponse {
+    encoded_jit_config: String,
+}
+
+
+#[derive(Deserialize)]
+struct Installation {
+    id: i64,
+}
+
+
+#[derive(Deserialize)]
+struct RunnerList {
+    runners: Vec<Runner>,
+}
+
+
+#[derive(Deserialize)]
+struct Runner {
+    id: i64,
+    name: String,
+}
+
+
+
+//------------------------------------------------------------------------------
+// Utility Functions
+//------------------------------------------------------------------------------
+
+
+fn client() -> Result<Client> {
+    let mut builder = Client::builder().timeout(REQUEST_TIMEOUT);
+    if let Ok(proxy) = env::var("PROXY_URL") {
+        builder = builder.proxy(Proxy::https(&proxy)?);
+    }
+    Ok(builder.build()?)
+}
+
+
+fn authorized(request: RequestBuilder, credentials: &str) -> RequestBuilder {
+    request
+        .header("Authorization", format!("Bearer {}", credentials))
+        .header("Accept", "application/vnd.github.v3+json")
+        .header(header::USER_AGENT, env!("CARGO_PKG_NAME"))
+}
+
+
+async fn send(request: RequestBuilder) -> Result<Response> {
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let url = response.url().to_string();
+        bail!("{} answered {}: {}", url, status, response.text().await.unwrap_or_default());
+    }
+    Ok(response)
+}
+
+
+/// Short lived JWT signed with the private key of the app
+fn app_jwt(app: &AppCredentials) -> Result<String> {
+    let pem = fs::read(&app.private_key)
+        .with_context(|| format!("Failed to read GitHub App key {}", app.private_key))?;
+
+    // Backdated a minute against clock drift, GitHub accepts at most 10 minutes validity
+    let now = Utc::now().timestamp();
+    let claims = Claims {
+        iat: now - 60,
+        exp: now + 9 * 60,
+        iss: app.app_id.clone(),
+    };
+    let key = EncodingKey::from_rsa_pem(&pem)?;
+    Ok(jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &key)?)
+}
+
+
+/// Environment variable suffix of an account, upper case with dashes as underscores
+fn env_suffix(owner: &str) -> String {
+    owner.to_uppercase().replace(['-', '.'], "_")
+}
+
+
+/// `GITHUB_APP_INSTALLATION_ID_<OWNER>`, or else looked up for the scope
+async fn installation_id(jwt: &str, scope: &Scope) -> Result<String> {
+    let variable = format!("GITHUB_APP_INSTALLATION_ID_{}", env_suffix(scope.owner()));
+    if let Ok(id) = env::var(variable) {
+        return Ok(id);
+    }
+
+    let url = format!("{}/{}/installation", *API_URL, scope.path());
+    let response = send(authorized(client()?.get(&url), jwt)).await?;
+    Ok(response.json::<Installation>().await?.id.to_string())
+}
+
+
+async fn installation_token(app: &AppCredentials, scope: &Scope) -> Result<String> {
+    // Not held while asking GitHub, which would hold up the other accounts
+    if let Some(token) = INSTALLATION_TOKENS.lock().await.get(scope.owner()) {
+        if token.expires_at - chrono::Duration::minutes(5) > Utc::now() {
+            return Ok(token.token.clone());
+        }
+    }
+
+    let jwt = app_jwt(app)?;
+    let installation = installation_id(&jwt, scope).await?;
+    let url = format!("{}/app/installations/{}/access_tokens", *API_URL, installation);
+    let token = send(authorized(client()?.post(&url), &jwt))
+        .await?
+        .json::<InstallationToken>()
+        .await?;
+
+    println!(
+        "Renewed GitHub App installation token for {}, valid until {}",
+        scope.owner(),
+        token.expires_at
+    );
+    let renewed = token.token.clone();
+    INSTALLATION_TOKENS.lock().await.insert(scope.owner().to_string(), token);
+    Ok(renewed)
+}
+
+
+pub fn default_scope() -> Option<Scope> {
+    DEFAULT_SCOPE.clone()
+}
+
+
+pub fn default_labels() -> Vec<String> {
+    RUNNER_LABELS.clone()
+}
+
+
+/// Labels are stored comma separated
+pub fn valid_labels(labels: &[String]) -> bool {
+    !labels.is_empty() && labels.iter().all(|label| !label.is_empty() && !label.contains(','))
+}
+
+
+/// Installation token of the GitHub App if one is configured. Otherwise the PAT of the
+/// account, `GITHUB_PAT_<OWNER>` with dashes as underscores, or else `GITHUB_PAT`.
+async fn credentials(scope: &Scope) -> Result<String> {
+    if let Some(app) = APP.as_ref() {
+        return installation_token(app, scope).await;
+    }
+
+    env::var(format!("GITHUB_PAT_{}", env_suffix(scope.owner())))
+        .or_else(|_| env::var("GITHUB_PAT"))
+        .with_context(|| format!("Neither a GitHub App nor a PAT is configured for {}", scope))
+}
+
+
+
+//------------------------------------------------------------------------------
+// GitHub API
+//------------------------------------------------------------------------------
+
+
+pub async fn registration_token(scope: &Scope) -> Result<String> {
+    let url = format!("{}/{}/actions/runners/registration-token", *API_URL, scope.path());
+    let response = send(authorized(client()?.post(&url), &credentials(scope).await?)).await?;
+    Ok(response.json::<TokenResponse>().await?.token)
+}
+
+
+/// Registers the runner right away, the config is only valid for a single job
+pub async fn jit_config(
+    scope: &Scope,
+    runner: &str,
+    labels: &[String],
+    group: i64,
+) -> Result<String> {
+    let url = format!("{}/{}/actions/runners/generate-jitconfig", *API_URL, scope.path());
+    let body = JitConfigRequest {
+        name: runner,
+        runner_group_id: group,
+        labels,
+        work_folder: "_work",
+    };
+    let request = authorized(client()?.post(&url), &credentials(scope).await?).json(&body);
+    let response = send(request).await?;
+    Ok(response.json::<JitConfigResponse>().await?.encoded_jit_config)
+}
+
+
+/// Removes the runner registered under the name, false if there is none
+pub async fn deregister_runner(scope: &Scope, runner: &str) -> Result<bool> {
+    let credentials = credentials(scope).await?;
+    let url = format!("{}/{}/actions/runners", *API_URL, scope.path());
+    let request = client()?.get(&url).query(&[("name", runner)]);
+    let list = send(authorized(request, &credentials)).await?.json::<RunnerList>().await?;
+
+    let Some(registered) = list.runners.into_iter().find(|r| r.name == runner) else {
+        return Ok(false);
+    };
+    let url = format!("{}/{}/actions/runners/{}", *API_URL, scope.path(), registered.id);
+    send(authorized(client()?.delete(&url), &credentials)).await?;
+    Ok(true)
+}
@@ -9,11 +9,38 @@
 
 use anyhow;
 use rocket::{serde::Deserialize, http::Status, serde::Serialize};
-use rocket_db_pools::sqlx::{Connection, SqliteConnection};
+use rocket::serde::json::{self, Json};
+use rocket::tokio::{
+    select,
+    sync::Notify,
+    time::{sleep, Duration, Instant},
+};
+use rocket_db_pools::sqlx::{Connection, SqliteConnection, SqlitePool};
 use rocket_okapi::okapi::schemars;
 use rocket_okapi::okapi::schemars::JsonSchema;
+use std::{env, sync::LazyLock};
 
-use crate::db::{self};
+use crate::{db, timestamp, vm};
+
+
+
+//------------------------------------------------------------------------------
+// Config
+//------------------------------------------------------------------------------
+
+
+/// Time a claim stays valid without being renewed. Defaults to `RUNNER_VALIDITY`, so a
+/// claim is not lost before the runner holding it is force reset anyway.
+static LEASE: LazyLock<i64> = LazyLock::new(|| {
+    let input = env::var("HARDWARE_LEASE").unwrap_or_default();
+    timestamp::parse_duration(&input).unwrap_or(*timestamp::VALIDITY)
+});
+
+/// Woken whenever a claim may have been granted, see `wait_for_claim`
+static CLAIM_EVENTS: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+/// Waiters re-check the database at least this often, even without an event
+const CLAIM_RECHECK: Duration = Duration::from_secs(5);
 
 
 
@@ -27,14 +54,37 @@ pub struct HardwareInfo {
     pub name: String,
     pub status: db::HardwareStatus,
     pub claimed_by: Option<String>,
+    pub board_type: Option<String>,
+    pub lease_expiry: Option<timestamp::Timestamp>,
+    pub claim: Option<ClaimMetadata>,
+}
+
+
+/// Describes the GitHub workflow a board was claimed for
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ClaimMetadata {
+    pub repository: Option<String>,
+    pub run_id: Option<i64>,
+    pub job: Option<String>,
+    pub sha: Option<String>,
 }
 
 impl HardwareInfo {
-    pub fn new(name: String, status: db::HardwareStatus, claimed_by: Option<String>) -> Self {
+    pub fn new(
+        name: String,
+        status: db::HardwareStatus,
+        claimed_by: Option<String>,
+        board_type: Option<String>,
+        lease_expiry: Option<timestamp::Timestamp>,
+        claim: Option<ClaimMetadata>,
+    ) -> Self {
         Self {
             name,
             status,
             claimed_by,
+            board_type,
+            lease_expiry,
+            claim,
         }
     }
 
@@ -49,6 +99,170 @@ impl HardwareInfo {
 }
 
 
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct NewHardware {
+    pub name: String,
+    pub status: Option<db::HardwareStatus>,
+    pub board_type: Option<String>,
+}
+
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct HardwareUpdate {
+    pub status: Option<db::HardwareStatus>,
+    pub board_type: Option<String>,
+}
+
+
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct QueueEntry {
+    pub runner: String,
+    pub enqueued_at: timestamp::Timestamp,
+}
+
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct QueuePosition {
+    pub hardware: String,
+    pub runner: String,
+    pub claimed: bool,
+    pub position: Option<i64>,
+}
+
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+pub struct MultiClaim {
+    #[serde(default)]
+    pub boards: Vec<String>,
+    #[serde(default)]
+    pub pools: Vec<String>,
+    #[serde(default)]
+    pub metadata: ClaimMetadata,
+}
+
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ClaimResult {
+    pub requested: String,
+    pub pool: bool,
+    pub hardware: Option<String>,
+    pub claimed: bool,
+    pub reason: Option<String>,
+}
+
+
+impl QueuePosition {
+    pub async fn retrieve(db: &mut SqliteConnection, hardware: &str, runner: &str) -> Option<Self> {
+        let claimed =
+            db::get_hardware_info(db, hardware).await.claimed_by.as_deref() == Some(runner);
+        let position = db::queue_position(db, hardware, runner).await;
+
+        if !claimed && position.is_none() {
+            return None;
+        }
+
+        Some(Self {
+            hardware: hardware.to_string(),
+            runner: runner.to_string(),
+            claimed,
+            position,
+        })
+    }
+}
+
+
+
+//------------------------------------------------------------------------------
+// Utility Functions
+//------------------------------------------------------------------------------
+
+
+/// Claims may come without metadata, but not with a malformed body
+pub fn claim_metadata(
+    body: Result<Json<ClaimMetadata>, json::Error<'_>>,
+) -> Result<ClaimMetadata, Status> {
+    match body {
+        Ok(metadata) => Ok(metadata.into_inner()),
+        Err(json::Error::Parse(body, _)) if body.trim().is_empty() => {
+            Ok(ClaimMetadata::default())
+        }
+        Err(e) => {
+            eprintln!("Invalid claim metadata: {}", e);
+            Err(Status::BadRequest)
+        }
+    }
+}
+
+
+/// Claims the board for the runner and starts a fresh lease
+async fn set_claimed(
+    db: &mut SqliteConnection,
+    hardware: &str,
+    runner: &str,
+    metadata: &ClaimMetadata,
+) -> anyhow::Result<()> {
+    db::update_hardware_status(db, hardware, Some(runner), db::HardwareStatus::CLAIMED).await?;
+    db::update_hardware_lease(db, hardware, timestamp::Timestamp::from_now(*LEASE).chrono()).await;
+    db::update_hardware_claim_metadata(db, hardware, metadata).await;
+    Ok(())
+}
+
+
+/// Drops any claim and lease held on the board
+async fn set_unclaimed(
+    db: &mut SqliteConnection,
+    hardware: &str,
+    status: db::HardwareStatus,
+) -> anyhow::Result<()> {
+    db::update_hardware_status(db, hardware, None, status).await?;
+    db::update_hardware_lease(db, hardware, None).await;
+    db::update_hardware_claim_metadata(db, hardware, &ClaimMetadata::default()).await;
+    Ok(())
+}
+
+
+/// Claims a board outside of the queue, which only succeeds if nobody else waits for it
+async fn claim_directly(
+    db: &mut SqliteConnection,
+    hardware: &str,
+    runner: &str,
+    metadata: &ClaimMetadata,
+) -> anyhow::Result<Result<(), Status>> {
+    match is_hardware_available(db, hardware).await {
+        Ok(true) => {}
+        Ok(false) => return Ok(Err(Status::Conflict)),
+        Err(_) => return Ok(Err(Status::NotFound)),
+    }
+
+    // Runners waiting in the queue take precedence over direct claims
+    if let Some(head) = db::queue_head(db, hardware).await {
+        if head != runner {
+            return Ok(Err(Status::Conflict));
+        }
+        db::dequeue_claim(db, hardware, runner).await;
+    }
+
+    set_claimed(db, hardware, runner, metadata).await?;
+    Ok(Ok(()))
+}
+
+
+/// Hands a free board to the runner at the head of its queue
+async fn grant_queued_claim(db: &mut SqliteConnection, hardware: &str) -> anyhow::Result<()> {
+    if db::HardwareStatus::FREE != db::get_hardware_status(db, hardware).await {
+        return Ok(());
+    }
+
+    if let Some(runner) = db::queue_head(db, hardware).await {
+        let metadata = db::get_queue_metadata(db, hardware, &runner).await;
+        set_claimed(db, hardware, &runner, &metadata).await?;
+        db::dequeue_claim(db, hardware, &runner).await;
+        println!("Granted hardware {} to queued runner {}", hardware, runner);
+    }
+    Ok(())
+}
+
+
 
 //------------------------------------------------------------------------------
 // Hardware Endpoint Logic
@@ -62,47 +276,126 @@ pub async fn is_hardware_available(
     if !db::hardware_exists(db, hardware).await {
         return Err(anyhow::anyhow!("Hardware does not exist"));
     }
-    return Ok(db::HardwareStatus::CLAIMED != db::get_hardware_status(db, hardware).await);
+    return Ok(db::HardwareStatus::FREE == db::get_hardware_status(db, hardware).await);
 }
 
 
 pub async fn hardware_info(db: &mut SqliteConnection) -> Vec<HardwareInfo> {
     let boards = db::hardware_board_list(db).await;
 
-    println!("Boards: {:?}", boards);
-
     let mut hardware_info: Vec<HardwareInfo> = Vec::new();
     for board in boards {
-        hardware_info.push(
-            if let Some(hwi) = HardwareInfo::retrieve(db, &board).await {
-                println!("HWI: {:?}", hwi);
-                hwi
-            } else {
-                eprintln!("Uhhh something went wrong :(");
-                continue;
-            },
-        )
+        match HardwareInfo::retrieve(db, &board).await {
+            Some(hwi) => hardware_info.push(hwi),
+            None => eprintln!("Hardware {} vanished while listing", board),
+        }
     }
 
     hardware_info
 }
 
 
+pub async fn hardware_create(db: &mut SqliteConnection, hardware: NewHardware) -> Status {
+    if !vm::valid_name(&hardware.name) {
+        eprintln!("Invalid hardware name {}", hardware.name);
+        return Status::BadRequest;
+    }
+    if db::hardware_exists(db, &hardware.name).await {
+        eprintln!("Hardware already exists");
+        return Status::Conflict;
+    }
+
+    let status = hardware.status.unwrap_or(db::HardwareStatus::FREE);
+    if status == db::HardwareStatus::CLAIMED {
+        eprintln!("Hardware can only be claimed by a runner");
+        return Status::BadRequest;
+    }
+    db::insert_hardware(db, &hardware.name, status).await;
+    db::update_hardware_type(db, &hardware.name, hardware.board_type.as_deref()).await;
+
+    println!("Added hardware {}", hardware.name);
+    Status::Created
+}
+
+
+pub async fn hardware_update(
+    db: &mut SqliteConnection,
+    hardware: &str,
+    update: HardwareUpdate,
+) -> anyhow::Result<Status> {
+    if !db::hardware_exists(db, hardware).await {
+        eprintln!("Hardware does not exist");
+        return Ok(Status::NotFound);
+    }
+
+    if let Some(status) = update.status {
+        if status == db::HardwareStatus::CLAIMED {
+            eprintln!("Hardware can only be claimed by a runner");
+            return Ok(Status::BadRequest);
+        }
+        // Setting the status explicitly drops any claim held on the board
+        set_unclaimed(db, hardware, status).await?;
+        grant_queued_claim(db, hardware).await?;
+        CLAIM_EVENTS.notify_waiters();
+    }
+
+    if let Some(board_type) = update.board_type {
+        db::update_hardware_type(db, hardware, Some(&board_type)).await;
+    }
+
+    println!("Updated hardware {}", hardware);
+    Ok(Status::Ok)
+}
+
+
+pub async fn hardware_delete(db: &mut SqliteConnection, hardware: &str) -> Status {
+    if !db::hardware_exists(db, hardware).await {
+        eprintln!("Hardware does not exist");
+        return Status::NotFound;
+    }
+
+    if db::HardwareStatus::CLAIMED == db::get_hardware_status(db, hardware).await {
+        eprintln!("Hardware is claimed, release first.");
+        return Status::Conflict;
+    }
+    db::delete_hardware(db, hardware).await;
+
+    println!("Removed hardware {}", hardware);
+    Status::Ok
+}
+
+
+pub async fn hardware_pool_info(
+    db: &mut SqliteConnection,
+    board_type: &str,
+) -> Option<Vec<HardwareInfo>> {
+    if !db::board_type_exists(db, board_type).await {
+        eprintln!("Board type does not exist");
+        return None;
+    }
+
+    let mut hardware_info: Vec<HardwareInfo> = Vec::new();
+    for board in db::hardware_of_type_list(db, board_type).await {
+        if let Some(hwi) = HardwareInfo::retrieve(db, &board).await {
+            hardware_info.push(hwi);
+        }
+    }
+    Some(hardware_info)
+}
+
+
 pub async fn claim_hardware(
     db: &mut SqliteConnection,
     hardware: &str,
     runner: &str,
+    metadata: ClaimMetadata,
 ) -> anyhow::Result<Status> {
     let mut tx = db.begin().await?;
 
-    match is_hardware_available(&mut tx, hardware).await {
-        Ok(true) => {}
-        Ok(false) => return Ok(Status::Conflict),
-        Err(_) => return Ok(Status::NotFound),
+    if let Err(status) = claim_directly(&mut tx, hardware, runner, &metadata).await? {
+        return Ok(status);
     }
 
-    db::update_hardware_status(&mut tx, hardware, runner, db::HardwareStatus::CLAIMED).await?;
-
     tx.commit().await?;
     Ok(Status::Ok)
 }
@@ -120,8 +413,263 @@ pub async fn release_hardware(
         Err(_) => return Ok(Status::NotFound),
     }
 
-    db::update_hardware_status(&mut tx, hardware, runner, db::HardwareStatus::FREE).await?;
+    if db::get_hardware_info(&mut tx, hardware).await.claimed_by.as_deref() != Some(runner) {
+        return Ok(Status::Conflict);
+    }
+
+    set_unclaimed(&mut tx, hardware, db::HardwareStatus::FREE).await?;
+    grant_queued_claim(&mut tx, hardware).await?;
+
+    tx.commit().await?;
+    CLAIM_EVENTS.notify_waiters();
+    Ok(Status::Ok)
+}
+
+
+pub async fn renew_hardware(
+    db: &mut SqliteConnection,
+    hardware: &str,
+    runner: &str,
+) -> anyhow::Result<Status> {
+    let mut tx = db.begin().await?;
+
+    if !db::hardware_exists(&mut tx, hardware).await {
+        return Ok(Status::NotFound);
+    }
+
+    let info = db::get_hardware_info(&mut tx, hardware).await;
+    if info.status != db::HardwareStatus::CLAIMED || info.claimed_by.as_deref() != Some(runner) {
+        return Ok(Status::Conflict);
+    }
+
+    db::update_hardware_lease(&mut tx, hardware, timestamp::Timestamp::from_now(*LEASE).chrono()).await;
 
     tx.commit().await?;
     Ok(Status::Ok)
 }
+
+
+/// Releases all boards whose lease lapsed, regardless of the runner's own reset timer
+pub async fn release_expired_leases(db: &mut SqliteConnection) {
+    for (hardware, runner) in db::get_hardware_with_expired_lease(db).await {
+        match release_hardware(db, &hardware, &runner).await {
+            Ok(Status::Ok) => println!("Lease of {} held by {} expired, released", hardware, runner),
+            _ => eprintln!("Failed to release {} after its lease expired", hardware),
+        }
+    }
+}
+
+
+/// Claims all requested boards and pools in one transaction, or none at all
+pub async fn claim_multiple_hardware(
+    db: &mut SqliteConnection,
+    runner: &str,
+    request: MultiClaim,
+) -> anyhow::Result<(Status, Vec<ClaimResult>)> {
+    if !db::runner_exists(db, runner).await {
+        eprintln!("Runner does not exist");
+        return Ok((Status::NotFound, Vec::new()));
+    }
+
+    let mut tx = db.begin().await?;
+    let mut failure: Option<Status> = None;
+    let mut results: Vec<ClaimResult> = Vec::new();
+
+    for board in request.boards {
+        let outcome = claim_directly(&mut tx, &board, runner, &request.metadata).await?;
+        results.push(ClaimResult {
+            hardware: Some(board.clone()),
+            requested: board,
+            pool: false,
+            claimed: outcome.is_ok(),
+            reason: outcome.err().map(|status| status.reason_lossy().to_string()),
+        });
+        failure = failure.or(outcome.err());
+    }
+
+    for board_type in request.pools {
+        // Boards claimed above are no longer free, so each pool entry gets its own board
+        let outcome = if !db::board_type_exists(&mut tx, &board_type).await {
+            Err(Status::NotFound)
+        } else if let Some(hardware) = db::get_free_hardware_of_type(&mut tx, &board_type).await {
+            set_claimed(&mut tx, &hardware, runner, &request.metadata).await?;
+            Ok(hardware)
+        } else {
+            Err(Status::Conflict)
+        };
+        results.push(ClaimResult {
+            requested: board_type,
+            pool: true,
+            hardware: outcome.as_ref().ok().cloned(),
+            claimed: outcome.is_ok(),
+            reason: outcome.as_ref().err().map(|status| status.reason_lossy().to_string()),
+        });
+        failure = failure.or(outcome.err());
+    }
+
+    if let Some(status) = failure {
+        // Dropping the transaction rolls back the claims that did succeed
+        for result in results.iter_mut().filter(|result| result.claimed) {
+            result.claimed = false;
+            result.reason = Some("Rolled back".to_string());
+        }
+        return Ok((status, results));
+    }
+
+    tx.commit().await?;
+
+    println!("Runner {} claimed {:?}", runner, results.iter().map(|r| &r.hardware).collect::<Vec<_>>());
+    Ok((Status::Ok, results))
+}
+
+
+pub async fn claim_pool_hardware(
+    db: &mut SqliteConnection,
+    board_type: &str,
+    runner: &str,
+    metadata: ClaimMetadata,
+) -> anyhow::Result<Result<HardwareInfo, Status>> {
+    if !db::runner_exists(db, runner).await {
+        eprintln!("Runner does not exist");
+        return Ok(Err(Status::NotFound));
+    }
+
+    let mut tx = db.begin().await?;
+
+    if !db::board_type_exists(&mut tx, board_type).await {
+        eprintln!("Board type does not exist");
+        return Ok(Err(Status::NotFound));
+    }
+
+    // Picking and claiming happen in one transaction, so two runners can never
+    // end up with the same board of a pool
+    let hardware = match db::get_free_hardware_of_type(&mut tx, board_type).await {
+        Some(hardware) => hardware,
+        None => return Ok(Err(Status::Conflict)),
+    };
+    set_claimed(&mut tx, &hardware, runner, &metadata).await?;
+
+    tx.commit().await?;
+
+    println!("Runner {} claimed {} from pool {}", runner, hardware, board_type);
+    Ok(Ok(db::get_hardware_info(db, &hardware).await))
+}
+
+
+pub async fn queue_info(db: &mut SqliteConnection, hardware: &str) -> Option<Vec<QueueEntry>> {
+    if !db::hardware_exists(db, hardware).await {
+        eprintln!("Hardware does not exist");
+        return None;
+    }
+
+    Some(
+        db::get_queue(db, hardware)
+            .await
+            .into_iter()
+            .map(|(runner, enqueued_at)| QueueEntry {
+                runner,
+                enqueued_at: timestamp::Timestamp::from(enqueued_at),
+            })
+            .collect(),
+    )
+}
+
+
+pub async fn queue_position(
+    db: &mut SqliteConnection,
+    hardware: &str,
+    runner: &str,
+) -> Option<QueuePosition> {
+    if !db::hardware_exists(db, hardware).await {
+        eprintln!("Hardware does not exist");
+        return None;
+    }
+
+    QueuePosition::retrieve(db, hardware, runner).await
+}
+
+
+pub async fn enqueue_claim(
+    db: &mut SqliteConnection,
+    hardware: &str,
+    runner: &str,
+    metadata: &ClaimMetadata,
+) -> anyhow::Result<Result<QueuePosition, Status>> {
+    if !db::hardware_exists(db, hardware).await || !db::runner_exists(db, runner).await {
+        eprintln!("Hardware or runner does not exist");
+        return Ok(Err(Status::NotFound));
+    }
+
+    let mut tx = db.begin().await?;
+
+    if db::get_hardware_info(&mut tx, hardware).await.claimed_by.as_deref() != Some(runner) {
+        db::enqueue_claim(&mut tx, hardware, runner, metadata).await;
+        grant_queued_claim(&mut tx, hardware).await?;
+    }
+
+    tx.commit().await?;
+    CLAIM_EVENTS.notify_waiters();
+
+    match QueuePosition::retrieve(db, hardware, runner).await {
+        Some(position) => Ok(Ok(position)),
+        None => Ok(Err(Status::InternalServerError)),
+    }
+}
+
+
+/// Enqueues the runner and blocks until the board is claimed for it or the timeout
+/// passes. Connections are only held while checking, not while waiting.
+pub async fn wait_for_claim(
+    pool: &SqlitePool,
+    hardware: &str,
+    runner: &str,
+    timeout: Duration,
+) -> anyhow::Result<Result<QueuePosition, Status>> {
+    let deadline = Instant::now() + timeout;
+
+    // Metadata is only taken from the first enqueue, so none is passed here
+    let metadata = ClaimMetadata::default();
+    match enqueue_claim(&mut *pool.acquire().await?, hardware, runner, &metadata).await? {
+        Ok(position) if position.claimed => return Ok(Ok(position)),
+        Ok(_) => {}
+        Err(status) => return Ok(Err(status)),
+    }
+
+    loop {
+        // Register for events before checking, so no grant slips in between
+        let event = CLAIM_EVENTS.notified();
+
+        match QueuePosition::retrieve(&mut *pool.acquire().await?, hardware, runner).await {
+            Some(position) if position.claimed => return Ok(Ok(position)),
+            Some(_) => {}
+            None => {
+                eprintln!("Runner {} is no longer queued for {}", runner, hardware);
+                return Ok(Err(Status::NotFound));
+            }
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(Err(Status::RequestTimeout));
+        }
+
+        select! {
+            _ = event => {}
+            _ = sleep(CLAIM_RECHECK.min(deadline - now)) => {}
+        }
+    }
+}
+
+
+pub async fn dequeue_claim(db: &mut SqliteConnection, hardware: &str, runner: &str) -> Status {
+    if !db::hardware_exists(db, hardware).await {
+        eprintln!("Hardware does not exist");
+        return Status::NotFound;
+    }
+
+    if !db::dequeue_claim(db, hardware, runner).await {
+        eprintln!("Runner is not queued for this hardware");
+        return Status::NotFound;
+    }
+    Status::Ok
+}
@@ -0,0 +1,76 @@
+//
+// Copyright (C) 2024, HENSOLDT Cyber GmbH
+// 
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// For commercial licensing, contact: info.cyber@hensoldt.net
+//
+
+
+use chrono::DateTime;
+use rocket::http::Status;
+use rocket::serde::{Deserialize, Serialize};
+use rocket_db_pools::sqlx::SqliteConnection;
+use rocket_okapi::okapi::{schemars, schemars::JsonSchema};
+use std::str::FromStr;
+
+use crate::{db, timestamp};
+
+
+
+//------------------------------------------------------------------------------
+// Data Structures
+//------------------------------------------------------------------------------
+
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct HistoryEntry {
+    pub time: timestamp::Timestamp,
+    pub event: db::HistoryEvent,
+    pub value: Option<String>,
+    pub runner: Option<String>,
+}
+
+impl HistoryEntry {
+    fn from(record: db::HistoryRecord) -> Self {
+        Self {
+            time: timestamp::Timestamp::from(record.Time),
+            event: db::HistoryEvent::from_str(&record.Event)
+                .expect("Invalid History Event: Database Corruption"),
+            value: record.Value,
+            runner: record.Runner,
+        }
+    }
+}
+
+
+
+//------------------------------------------------------------------------------
+// History Endpoint Logic
+//------------------------------------------------------------------------------
+
+
+/// History of a runner or board, optionally limited to [from, to] in unix seconds
+pub async fn history(
+    db: &mut SqliteConnection,
+    kind: db::HistoryKind,
+    subject: &str,
+    from: Option<i64>,
+    to: Option<i64>,
+) -> Result<Vec<HistoryEntry>, Status> {
+    // Bounds chrono can not represent are answered with 400
+    let bound = |t: Option<i64>| match t {
+        Some(t) => DateTime::from_timestamp(t, 0)
+            .map(|dt| Some(dt.naive_utc()))
+            .ok_or(Status::BadRequest),
+        None => Ok(None),
+    };
+    let from = bound(from)?;
+    let to = bound(to)?;
+
+    Ok(db::get_history(db, kind, subject, from, to)
+        .await
+        .into_iter()
+        .map(HistoryEntry::from)
+        .collect())
+}
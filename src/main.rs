@@ -7,12 +7,19 @@
 //
 
 
+mod command_task;
 mod db;
+mod github;
 mod hardware;
+mod history;
+mod reconcile_task;
 mod reset_task;
 mod runners;
+mod stats;
 mod timestamp;
+mod transition;
 mod vm;
+mod webhook;
 
 #[macro_use]
 extern crate rocket;
@@ -20,8 +27,10 @@ extern crate rocket;
 use rocket::{
     fairing::{self, AdHoc},
     http::Status,
-    serde::json::Json,
-    Build, Rocket,
+    response::status,
+    serde::json::{self, Json},
+    tokio::time::Duration,
+    Build, Rocket, State,
 };
 use rocket_db_pools::{sqlx, Connection, Database};
 use rocket_okapi::{openapi, openapi_get_routes, swagger_ui::*};
@@ -50,19 +59,79 @@ async fn runner_info(mut db: Connection<db::RunnerDb>, runner_id: &str,) -> Resu
 }
 
 
+#[openapi(tag = "Runner", ignore = "db")]
+#[post("/runner", data = "<runner>")]
+async fn runner_create(mut db: Connection<db::RunnerDb>, runner: Json<runners::NewRunner>) -> Status {
+    runners::runner_create(&mut db, runner.into_inner()).await
+}
+
+
+#[openapi(tag = "Runner", ignore = "db")]
+#[patch("/runner/<runner_id>", data = "<update>")]
+async fn runner_update(
+    mut db: Connection<db::RunnerDb>,
+    runner_id: &str,
+    update: Json<runners::RunnerUpdate>,
+) -> Result<Status, transition::TransitionError> {
+    runners::runner_update(&mut db, runner_id, update.into_inner()).await
+}
+
+
+#[openapi(tag = "Runner", ignore = "db")]
+#[delete("/runner/<runner_id>")]
+async fn runner_delete(mut db: Connection<db::RunnerDb>, runner_id: &str) -> Status {
+    runners::runner_delete(&mut db, runner_id).await
+}
+
+
 #[openapi(tag = "Runner", ignore = "db")]
 #[get("/runner/<runner_id>/registration-token")]
 async fn runner_registration_token(
     db: Connection<db::RunnerDb>,
     runner_id: &str,
-) -> Result<String, Status> {
+) -> Result<String, status::Custom<String>> {
     runners::runner_return_github_token(db, runner_id).await
 }
 
 
+#[openapi(tag = "Runner", ignore = "db")]
+#[get("/runner/<runner_id>/registration")]
+async fn runner_registration(
+    db: Connection<db::RunnerDb>,
+    runner_id: &str,
+) -> Result<Json<runners::Registration>, status::Custom<String>> {
+    Ok(Json(runners::runner_return_registration(db, runner_id).await?))
+}
+
+
+#[openapi(tag = "Runner", ignore = "db")]
+#[get("/runner/<runner_id>/jitconfig")]
+async fn runner_jit_config(
+    db: Connection<db::RunnerDb>,
+    runner_id: &str,
+) -> Result<String, status::Custom<String>> {
+    runners::runner_return_jit_config(db, runner_id).await
+}
+
+
+#[openapi(tag = "Runner", ignore = "db")]
+#[get("/runner/<runner_id>/history?<from>&<to>")]
+async fn runner_history(
+    mut db: Connection<db::RunnerDb>,
+    runner_id: &str,
+    from: Option<i64>,
+    to: Option<i64>,
+) -> Result<Json<Vec<history::HistoryEntry>>, Status> {
+    Ok(Json(history::history(&mut db, db::HistoryKind::RUNNER, runner_id, from, to).await?))
+}
+
+
 #[openapi(tag = "Runner", ignore = "db")]
 #[post("/runner/<runner_id>/launch")]
-async fn runner_launch(mut db: Connection<db::RunnerDb>, runner_id: &str) -> Status {
+async fn runner_launch(
+    mut db: Connection<db::RunnerDb>,
+    runner_id: &str,
+) -> Result<Status, transition::TransitionError> {
     runners::runner_launch(&mut db, runner_id).await
 }
 
@@ -70,28 +139,83 @@ async fn runner_launch(mut db: Connection<db::RunnerDb>, runner_id: &str) -> Sta
 // VM Control functions
 #[openapi(tag = "Runner", ignore = "db")]
 #[post("/runner/<runner_id>/vm/reset")]
-async fn runner_vm_reset(mut db: Connection<db::RunnerDb>, runner_id: &str) -> Status {
+async fn runner_vm_reset(
+    mut db: Connection<db::RunnerDb>,
+    runner_id: &str,
+) -> Result<Status, transition::TransitionError> {
     runners::runner_reset(&mut db, runner_id).await
 }
 
 
 #[openapi(tag = "Runner", ignore = "db")]
-#[post("/runner/<runner_id>/vm/snapshot")]
-async fn runner_vm_snapshot(db: Connection<db::RunnerDb>, runner_id: &str) -> Status {
-    runners::vm_snapshot(db, runner_id).await
+#[post("/runner/<runner_id>/vm/snapshot", data = "<snapshot>")]
+async fn runner_vm_snapshot(
+    mut db: Connection<db::RunnerDb>,
+    runner_id: &str,
+    snapshot: Option<Json<runners::NewSnapshot>>,
+) -> Result<Status, transition::TransitionError> {
+    let snapshot = snapshot.map(Json::into_inner).unwrap_or_default();
+    runners::vm_snapshot(&mut db, runner_id, snapshot).await
+}
+
+
+#[openapi(tag = "Runner", ignore = "db")]
+#[post("/runner/<runner_id>/vm/snapshot/due")]
+async fn runner_vm_snapshot_due(
+    mut db: Connection<db::RunnerDb>,
+    runner_id: &str,
+) -> Result<Status, transition::TransitionError> {
+    runners::vm_snapshot_if_due(&mut db, runner_id).await
+}
+
+
+#[openapi(tag = "Runner", ignore = "db")]
+#[get("/runner/<runner_id>/vm/snapshots")]
+async fn runner_vm_snapshots(
+    mut db: Connection<db::RunnerDb>,
+    runner_id: &str,
+) -> Result<Json<Vec<runners::SnapshotInfo>>, Status> {
+    runners::vm_snapshots(&mut db, runner_id).await.map(Json)
+}
+
+
+#[openapi(tag = "Runner", ignore = "db")]
+#[post("/runner/<runner_id>/vm/revert/<snapshot>")]
+async fn runner_vm_revert(
+    mut db: Connection<db::RunnerDb>,
+    runner_id: &str,
+    snapshot: &str,
+) -> Result<Status, transition::TransitionError> {
+    runners::vm_revert(&mut db, runner_id, snapshot).await
+}
+
+
+#[openapi(tag = "Runner", ignore = "db")]
+#[get("/runner/<runner_id>/vm/commands")]
+async fn runner_vm_commands(
+    mut db: Connection<db::RunnerDb>,
+    runner_id: &str,
+) -> Result<Json<Vec<vm::QueuedCommand>>, Status> {
+    runners::vm_commands(&mut db, runner_id).await.map(Json)
 }
 
 
 #[openapi(tag = "Runner", ignore = "db")]
 #[post("/runner/<runner_id>/vm/start")]
-async fn runner_vm_start(mut db: Connection<db::RunnerDb>, runner_id: &str) -> Status {
+async fn runner_vm_start(
+    mut db: Connection<db::RunnerDb>,
+    runner_id: &str,
+) -> Result<Status, transition::TransitionError> {
     runners::vm_start(&mut db, &runner_id).await
 }
 
 
 #[openapi(tag = "Runner", ignore = "db")]
 #[post("/runner/<runner_id>/vm/stop")]
-async fn runner_vm_stop(mut db: Connection<db::RunnerDb>, runner_id: &str) -> Status {
+async fn runner_vm_stop(
+    mut db: Connection<db::RunnerDb>,
+    runner_id: &str,
+) -> Result<Status, transition::TransitionError> {
     runners::vm_stop(&mut db, runner_id).await
 }
 
@@ -110,6 +234,40 @@ async fn hardware_info(
 }
 
 
+#[openapi(tag = "Hardware", ignore = "db")]
+#[post("/hardware", data = "<hardware>")]
+async fn hardware_create(
+    mut db: Connection<db::RunnerDb>,
+    hardware: Json<hardware::NewHardware>,
+) -> Status {
+    hardware::hardware_create(&mut db, hardware.into_inner()).await
+}
+
+
+#[openapi(tag = "Hardware", ignore = "db")]
+#[patch("/hardware/<board_id>", data = "<update>")]
+async fn hardware_update(
+    mut db: Connection<db::RunnerDb>,
+    board_id: &str,
+    update: Json<hardware::HardwareUpdate>,
+) -> Result<Status, transition::TransitionError> {
+    match hardware::hardware_update(&mut db, board_id, update.into_inner()).await {
+        Ok(status) => Ok(status),
+        Err(e) => match e.downcast::<transition::TransitionError>() {
+            Ok(e) => Err(e),
+            Err(_) => Ok(Status::InternalServerError),
+        },
+    }
+}
+
+
+#[openapi(tag = "Hardware", ignore = "db")]
+#[delete("/hardware/<board_id>")]
+async fn hardware_delete(mut db: Connection<db::RunnerDb>, board_id: &str) -> Status {
+    hardware::hardware_delete(&mut db, board_id).await
+}
+
+
 #[openapi(tag = "Hardware", ignore = "db")]
 #[get("/hardware/<board_id>/info")]
 async fn hardware_board_info(
@@ -124,6 +282,18 @@ async fn hardware_board_info(
 }
 
 
+#[openapi(tag = "Hardware", ignore = "db")]
+#[get("/hardware/<board_id>/history?<from>&<to>")]
+async fn hardware_board_history(
+    mut db: Connection<db::RunnerDb>,
+    board_id: &str,
+    from: Option<i64>,
+    to: Option<i64>,
+) -> Result<Json<Vec<history::HistoryEntry>>, Status> {
+    Ok(Json(history::history(&mut db, db::HistoryKind::HARDWARE, board_id, from, to).await?))
+}
+
+
 #[openapi(tag = "Hardware", ignore = "db")]
 #[get("/hardware/<board_id>/available")]
 async fn hardware_board_available(
@@ -138,13 +308,64 @@ async fn hardware_board_available(
 
 
 #[openapi(tag = "Hardware", ignore = "db")]
-#[post("/hardware/<board_id>/claim/<runner>")]
+#[post("/hardware/<board_id>/claim/<runner>", data = "<metadata>")]
 async fn hardware_board_claim(
     mut db: Connection<db::RunnerDb>,
     board_id: &str,
     runner: &str,
+    metadata: Result<Json<hardware::ClaimMetadata>, json::Error<'_>>,
 ) -> Status {
-    return hardware::claim_hardware(&mut db, board_id, runner)
+    let metadata = match hardware::claim_metadata(metadata) {
+        Ok(metadata) => metadata,
+        Err(status) => return status,
+    };
+    return hardware::claim_hardware(&mut db, board_id, runner, metadata)
+        .await
+        .unwrap_or(Status::InternalServerError);
+}
+
+
+#[openapi(tag = "Hardware", ignore = "db")]
+#[post("/hardware/claim/<runner>", data = "<request>")]
+async fn hardware_claim_multiple(
+    mut db: Connection<db::RunnerDb>,
+    runner: &str,
+    request: Json<hardware::MultiClaim>,
+) -> status::Custom<Json<Vec<hardware::ClaimResult>>> {
+    match hardware::claim_multiple_hardware(&mut db, runner, request.into_inner()).await {
+        Ok((code, results)) => status::Custom(code, Json(results)),
+        Err(_) => status::Custom(Status::InternalServerError, Json(Vec::new())),
+    }
+}
+
+
+#[openapi(tag = "Hardware", ignore = "db")]
+#[get("/hardware/<board_id>/claim/<runner>/wait?<timeout>")]
+async fn hardware_board_claim_wait(
+    db: &State<db::RunnerDb>,
+    board_id: &str,
+    runner: &str,
+    timeout: Option<u64>,
+) -> Result<Json<hardware::QueuePosition>, Status> {
+    // Seconds, capped so proxies in between do not cut the connection
+    let timeout = Duration::from_secs(timeout.unwrap_or(60).min(300));
+
+    match hardware::wait_for_claim(db, board_id, runner, timeout).await {
+        Ok(Ok(position)) => Ok(Json(position)),
+        Ok(Err(status)) => Err(status),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+
+#[openapi(tag = "Hardware", ignore = "db")]
+#[post("/hardware/<board_id>/renew/<runner>")]
+async fn hardware_board_renew(
+    mut db: Connection<db::RunnerDb>,
+    board_id: &str,
+    runner: &str,
+) -> Status {
+    return hardware::renew_hardware(&mut db, board_id, runner)
         .await
         .unwrap_or(Status::InternalServerError);
 }
@@ -163,6 +384,140 @@ async fn hardware_board_release(
 }
 
 
+#[openapi(tag = "Hardware", ignore = "db")]
+#[get("/hardware/<board_id>/queue")]
+async fn hardware_board_queue(
+    mut db: Connection<db::RunnerDb>,
+    board_id: &str,
+) -> Result<Json<Vec<hardware::QueueEntry>>, Status> {
+    match hardware::queue_info(&mut db, board_id).await {
+        Some(queue) => Ok(Json(queue)),
+        None => Err(Status::NotFound),
+    }
+}
+
+
+#[openapi(tag = "Hardware", ignore = "db")]
+#[get("/hardware/<board_id>/queue/<runner>", rank = 2)] // yield to /hardware/pool/<board_type>/info
+async fn hardware_board_queue_position(
+    mut db: Connection<db::RunnerDb>,
+    board_id: &str,
+    runner: &str,
+) -> Result<Json<hardware::QueuePosition>, Status> {
+    match hardware::queue_position(&mut db, board_id, runner).await {
+        Some(position) => Ok(Json(position)),
+        None => Err(Status::NotFound),
+    }
+}
+
+
+#[openapi(tag = "Hardware", ignore = "db")]
+#[post("/hardware/<board_id>/queue/<runner>", data = "<metadata>")]
+async fn hardware_board_enqueue(
+    mut db: Connection<db::RunnerDb>,
+    board_id: &str,
+    runner: &str,
+    metadata: Result<Json<hardware::ClaimMetadata>, json::Error<'_>>,
+) -> Result<Json<hardware::QueuePosition>, Status> {
+    let metadata = hardware::claim_metadata(metadata)?;
+    match hardware::enqueue_claim(&mut db, board_id, runner, &metadata).await {
+        Ok(Ok(position)) => Ok(Json(position)),
+        Ok(Err(status)) => Err(status),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+
+#[openapi(tag = "Hardware", ignore = "db")]
+#[delete("/hardware/<board_id>/queue/<runner>")]
+async fn hardware_board_dequeue(
+    mut db: Connection<db::RunnerDb>,
+    board_id: &str,
+    runner: &str,
+) -> Status {
+    hardware::dequeue_claim(&mut db, board_id, runner).await
+}
+
+
+#[openapi(tag = "Hardware", ignore = "db")]
+#[get("/hardware/pool/<board_type>/info")]
+async fn hardware_pool_info(
+    mut db: Connection<db::RunnerDb>,
+    board_type: &str,
+) -> Result<Json<Vec<hardware::HardwareInfo>>, Status> {
+    match hardware::hardware_pool_info(&mut db, board_type).await {
+        Some(info) => Ok(Json(info)),
+        None => Err(Status::NotFound),
+    }
+}
+
+
+#[openapi(tag = "Hardware", ignore = "db")]
+#[post("/hardware/pool/<board_type>/claim/<runner>", data = "<metadata>")]
+async fn hardware_pool_claim(
+    mut db: Connection<db::RunnerDb>,
+    board_type: &str,
+    runner: &str,
+    metadata: Result<Json<hardware::ClaimMetadata>, json::Error<'_>>,
+) -> Result<Json<hardware::HardwareInfo>, Status> {
+    let metadata = hardware::claim_metadata(metadata)?;
+    match hardware::claim_pool_hardware(&mut db, board_type, runner, metadata).await {
+        Ok(Ok(info)) => Ok(Json(info)),
+        Ok(Err(status)) => Err(status),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+
+
+//------------------------------------------------------------------------------
+// GitHub
+//------------------------------------------------------------------------------
+
+
+#[openapi(tag = "GitHub", ignore = "db")]
+#[post("/github/webhook", data = "<payload>")]
+async fn github_webhook(
+    mut db: Connection<db::RunnerDb>,
+    headers: webhook::WebhookHeaders,
+    payload: Vec<u8>,
+) -> Result<Status, status::Custom<String>> {
+    webhook::handle(&mut db, headers, &payload).await
+}
+
+
+
+//------------------------------------------------------------------------------
+// Statistics
+//------------------------------------------------------------------------------
+
+
+#[openapi(tag = "Statistics", ignore = "db")]
+#[get("/stats/hardware?<from>&<to>&<format>")]
+async fn stats_hardware(
+    mut db: Connection<db::RunnerDb>,
+    from: Option<i64>,
+    to: Option<i64>,
+    format: Option<stats::ReportFormat>,
+) -> Result<stats::Report, Status> {
+    let format = format.unwrap_or(stats::ReportFormat::json);
+    stats::hardware_stats(&mut db, from, to, format).await
+}
+
+
+#[openapi(tag = "Statistics", ignore = "db")]
+#[get("/stats/runners?<from>&<to>&<format>")]
+async fn stats_runners(
+    mut db: Connection<db::RunnerDb>,
+    from: Option<i64>,
+    to: Option<i64>,
+    format: Option<stats::ReportFormat>,
+) -> Result<stats::Report, Status> {
+    let format = format.unwrap_or(stats::ReportFormat::json);
+    stats::runner_stats(&mut db, from, to, format).await
+}
+
+
 
 //------------------------------------------------------------------------------
 // Sqlx Migrations
@@ -195,22 +550,50 @@ async fn rocket() -> _ {
         .attach(db::RunnerDb::init())
         .attach(AdHoc::try_on_ignite("SQLx Migrations", run_migrations))
         .attach(reset_task::RunnerResetTask)
+        .attach(command_task::VmCommandTask)
+        .attach(reconcile_task::PowerStateReconcileTask)
         .mount(
             "/",
             openapi_get_routes![
                 runner_info,
                 runners_info,
+                runner_create,
+                runner_update,
+                runner_delete,
                 runner_registration_token,
+                runner_registration,
+                runner_jit_config,
+                runner_history,
                 runner_launch,
                 runner_vm_reset,
                 runner_vm_snapshot,
+                runner_vm_snapshot_due,
+                runner_vm_snapshots,
+                runner_vm_revert,
+                runner_vm_commands,
                 runner_vm_start,
                 runner_vm_stop,
                 hardware_info,
+                hardware_create,
+                hardware_update,
+                hardware_delete,
                 hardware_board_info,
                 hardware_board_claim,
+                hardware_board_claim_wait,
+                hardware_claim_multiple,
                 hardware_board_available,
+                hardware_board_history,
+                hardware_board_renew,
                 hardware_board_release,
+                hardware_board_queue,
+                hardware_board_queue_position,
+                hardware_board_enqueue,
+                hardware_board_dequeue,
+                hardware_pool_info,
+                hardware_pool_claim,
+                github_webhook,
+                stats_hardware,
+                stats_runners,
             ],
         )
         .mount(
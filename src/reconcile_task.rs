@@ -0,0 +1,69 @@
+//
+// Copyright (C) 2024, HENSOLDT Cyber GmbH
+// 
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// For commercial licensing, contact: info.cyber@hensoldt.net
+//
+
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    tokio::time::{interval, Duration},
+    Build, Rocket,
+};
+use rocket_db_pools::{
+    sqlx::{pool::PoolConnection, Sqlite},
+    Database,
+};
+
+use crate::{db, runners};
+
+//------------------------------------------------------------------------------
+// Reconciliation Logic
+//------------------------------------------------------------------------------
+
+
+async fn reconcile_task(mut db: PoolConnection<Sqlite>) {
+    let mut interval = interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+
+        let runners = db::runner_id_list(&mut *db).await;
+
+        for runner in runners {
+            runners::reconcile_power_state(&mut *db, &runner).await;
+        }
+    }
+}
+
+
+
+//------------------------------------------------------------------------------
+// Fairing Setup
+//------------------------------------------------------------------------------
+
+
+pub struct PowerStateReconcileTask;
+
+#[rocket::async_trait]
+impl Fairing for PowerStateReconcileTask {
+    fn info(&self) -> Info {
+        Info {
+            name: "Power State Reconcile Task",
+            kind: Kind::Ignite | Kind::Liftoff,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> Result<Rocket<Build>, Rocket<Build>> {
+        let db_pool = match db::RunnerDb::fetch(&rocket) {
+            Some(pool) => pool,
+            None => {
+                eprintln!("Failed to fetch the database pool");
+                return Err(rocket);
+            }
+        };
+        rocket::tokio::spawn(reconcile_task(db_pool.acquire().await.unwrap()));
+        Ok(rocket)
+    }
+}
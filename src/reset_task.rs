@@ -18,7 +18,7 @@ use rocket_db_pools::{
 };
 use chrono::Utc;
 
-use crate::{db, runners};
+use crate::{db, hardware, runners};
 
 //------------------------------------------------------------------------------
 // Reset Logic
@@ -30,15 +30,32 @@ async fn reset_task(mut db: PoolConnection<Sqlite>) {
     loop {
         interval.tick().await;
 
+        hardware::release_expired_leases(&mut *db).await;
+
         let runners = runners::runners_info(&mut *db).await;
 
         for runner in runners {
             if let Some(time_to_reset) = runner.time_to_reset {
                 if time_to_reset.unix() < Utc::now().timestamp() {
-                    runners::runner_reset(&mut *db, &runner.name).await;
+                    if let Err(e) = runners::runner_reset(&mut *db, &runner.name).await {
+                        eprintln!("{}", e);
+                        continue;
+                    }
+                    db::insert_history(
+                        &mut *db,
+                        db::HistoryKind::RUNNER,
+                        &runner.name,
+                        db::HistoryEvent::FORCE_RESET,
+                        None,
+                        Some(&runner.name),
+                    )
+                    .await;
                     println!("Force reset of {}", runner.name);
+                    continue;
                 }
             }
+
+            runners::reset_if_idle(&mut *db, &runner).await;
         }
     }
 }
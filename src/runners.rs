@@ -7,17 +7,33 @@
 //
 
 
-use reqwest::{header, Client, Proxy};
 use rocket::http::Status;
+use rocket::response::status;
 use rocket::serde::{Deserialize, Serialize};
 use rocket_db_pools::{Connection, sqlx::SqliteConnection};
 
 use rocket_okapi::okapi::{schemars, schemars::JsonSchema};
-use std::env;
+use chrono::Utc;
+use std::{env, sync::LazyLock};
+use std::str::FromStr;
 
 
 use crate::hardware;
-use crate::{db, timestamp, vm};
+use crate::{db, github, timestamp, transition, vm};
+
+
+
+//------------------------------------------------------------------------------
+// Config
+//------------------------------------------------------------------------------
+
+
+/// Time a registered runner may wait for a job before it is reset
+static IDLE_RESET_AFTER: LazyLock<i64> = LazyLock::new(|| {
+    let input = env::var("IDLE_RESET_AFTER").unwrap_or("7day".to_string());
+    timestamp::parse_duration(&input).unwrap_or(7 * 24 * 60 * 60) // Default value 7 Days
+});
+
 
 
 //------------------------------------------------------------------------------
@@ -30,14 +46,32 @@ pub struct RunnerInfo {
     pub name: String,
     pub status: db::RunnerStatus,
     pub time_to_reset: Option<timestamp::Timestamp>,
+    pub claimed_hardware: Vec<hardware::HardwareInfo>,
+    pub backend: vm::BackendKind,
+    /// `<org>` or `<owner>/<repo>` the runner registers with, None if not configured
+    pub scope: Option<String>,
+    pub labels: Vec<String>,
+    pub runner_group: i64,
+    pub last_command: Option<vm::CommandOutcome>,
+    pub power_state: Option<vm::PowerState>,
+    pub last_snapshot: Option<timestamp::Timestamp>,
+    pub last_active: Option<timestamp::Timestamp>,
+    pub idle_reset_at: Option<timestamp::Timestamp>,
+    pub job: Option<RunnerJob>,
 }
 
 impl RunnerInfo {
-    pub fn new(name: String, status: db::RunnerStatus, time_to_reset: Option<timestamp::Timestamp>) -> Self {
-        Self {
-            name,
-            status,
-            time_to_reset,
+    /// Runners waiting for a job are reset once they idled for `IDLE_RESET_AFTER`
+    pub fn idle_reset_at(
+        status: &db::RunnerStatus,
+        time_to_reset: &Option<timestamp::Timestamp>,
+        last_active: &Option<timestamp::Timestamp>,
+    ) -> Option<timestamp::Timestamp> {
+        match (status, time_to_reset, last_active) {
+            (db::RunnerStatus::IDLE, None, Some(last_active)) => Some(
+                timestamp::Timestamp::from_unix(last_active.unix() + *IDLE_RESET_AFTER),
+            ),
+            _ => None,
         }
     }
 
@@ -52,11 +86,77 @@ impl RunnerInfo {
 }
 
 
-#[derive(Serialize, Deserialize, JsonSchema)]
-pub struct TokenResponse {
-    token: String,
+/// Workflow job the runner works on, as reported by the GitHub webhook
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RunnerJob {
+    pub id: i64,
+    pub run_id: i64,
+    pub name: String,
+    pub repository: String,
+    pub url: Option<String>,
+}
+
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct NewRunner {
+    pub name: String,
+    pub status: Option<db::RunnerStatus>,
+    pub backend: Option<vm::BackendKind>,
+    pub scope: Option<String>,
+    pub labels: Option<Vec<String>>,
+    pub runner_group: Option<i64>,
+}
+
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct RunnerUpdate {
+    pub status: Option<db::RunnerStatus>,
+    pub backend: Option<vm::BackendKind>,
+    pub scope: Option<String>,
+    pub labels: Option<Vec<String>>,
+    pub runner_group: Option<i64>,
+}
+
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct SnapshotInfo {
+    pub name: String,
+    pub base_image: Option<String>,
+    pub created_at: timestamp::Timestamp,
+    pub available: bool,
+    pub current: bool,
+}
+
+impl SnapshotInfo {
+    fn from(record: db::SnapshotRecord, current: Option<&str>) -> Self {
+        Self {
+            current: current == Some(record.Name.as_str()),
+            name: record.Name,
+            base_image: record.BaseImage,
+            created_at: timestamp::Timestamp::from(record.CreatedAt),
+            available: record.Available,
+        }
+    }
+}
+
+
+/// Registration token together with the organization or repository URL it is valid for
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Registration {
+    pub url: String,
+    pub token: String,
+}
+
+
+/// Both optional, the name defaults to `<runner>-<YYYYMMDDhhmmss>`
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+pub struct NewSnapshot {
+    pub name: Option<String>,
+    pub base_image: Option<String>,
 }
 
+
+
 //------------------------------------------------------------------------------
 // Runner Endpoint Logic
 //------------------------------------------------------------------------------
@@ -80,27 +180,467 @@ pub async fn runners_info(mut db: &mut SqliteConnection) -> Vec<RunnerInfo> {
     runner_info
 }
 
-pub async fn runner_launch(db: &mut SqliteConnection, runner: &str) -> Status {
+pub async fn runner_create(db: &mut SqliteConnection, runner: NewRunner) -> Status {
+    if !vm::valid_name(&runner.name) {
+        eprintln!("Invalid runner name {}", runner.name);
+        return Status::BadRequest;
+    }
+    if db::runner_exists(db, &runner.name).await {
+        eprintln!("Runner already exists");
+        return Status::Conflict;
+    }
+    if !runner.labels.as_deref().map_or(true, github::valid_labels) {
+        eprintln!("Invalid runner labels");
+        return Status::BadRequest;
+    }
+    let scope = match runner.scope.as_deref().map(github::Scope::parse) {
+        Some(None) => {
+            eprintln!("Invalid runner scope");
+            return Status::BadRequest;
+        }
+        scope => scope.flatten(),
+    };
+
+    let status = runner.status.unwrap_or(db::RunnerStatus::RESETTING);
+    db::insert_runner(db, &runner.name, status).await;
+    db::update_runner_backend(db, &runner.name, runner.backend).await;
+    db::update_runner_scope(db, &runner.name, scope.as_ref()).await;
+    db::update_runner_labels(db, &runner.name, runner.labels.as_deref()).await;
+    db::update_runner_group(db, &runner.name, runner.runner_group).await;
+
+    println!("Registered runner {}", runner.name);
+    Status::Created
+}
+
+pub async fn runner_update(
+    db: &mut SqliteConnection,
+    runner: &str,
+    update: RunnerUpdate,
+) -> Result<Status, transition::TransitionError> {
+    if !db::runner_exists(db, runner).await {
+        eprintln!("Runner does not exist");
+        return Ok(Status::NotFound);
+    }
+    if !update.labels.as_deref().map_or(true, github::valid_labels) {
+        eprintln!("Invalid runner labels");
+        return Ok(Status::BadRequest);
+    }
+    let scope = match update.scope.as_deref().map(github::Scope::parse) {
+        Some(None) => {
+            eprintln!("Invalid runner scope");
+            return Ok(Status::BadRequest);
+        }
+        scope => scope.flatten(),
+    };
+
+    if let Some(status) = update.status {
+        db::update_runner_status(db, runner, status).await?;
+    }
+
+    if let Some(backend) = update.backend {
+        db::update_runner_backend(db, runner, Some(backend)).await;
+    }
+
+    if let Some(scope) = scope {
+        db::update_runner_scope(db, runner, Some(&scope)).await;
+    }
+
+    if let Some(labels) = update.labels {
+        db::update_runner_labels(db, runner, Some(&labels)).await;
+    }
+
+    if let Some(group) = update.runner_group {
+        db::update_runner_group(db, runner, Some(group)).await;
+    }
+
+    println!("Updated runner {}", runner);
+    Ok(Status::Ok)
+}
+
+pub async fn runner_delete(db: &mut SqliteConnection, runner: &str) -> Status {
     if !db::runner_exists(db, runner).await {
         eprintln!("Runner does not exist");
         return Status::NotFound;
     }
 
-    if db::get_runner_info(db, runner).await.time_to_reset.is_none() {
+    release_hardware(db, runner).await; // release all hardware claimed by runner
+    db::delete_runner(db, runner).await;
+
+    println!("Deleted runner {}", runner);
+    Status::Ok
+}
+
+pub async fn runner_launch(
+    db: &mut SqliteConnection,
+    runner: &str,
+) -> Result<Status, transition::TransitionError> {
+    if !db::runner_exists(db, runner).await {
+        eprintln!("Runner does not exist");
+        return Ok(Status::NotFound);
+    }
+
+    let info = db::get_runner_info(db, runner).await;
+    if info.time_to_reset.is_some() {
+        // The job started webhook may beat the job started hook of the runner
+        if info.status == db::RunnerStatus::RUNNING && info.job.is_some() {
+            println!("Runner {} already launched for its job", runner);
+            return Ok(Status::Ok);
+        }
         eprintln!("Runner is already running, reset first.");
-        return Status::Conflict;
+        return Ok(Status::Conflict);
     }
 
+    db::update_runner_status(db, runner, db::RunnerStatus::RUNNING).await?;
     let timestamp = timestamp::Timestamp::new().chrono();
     db::update_runner_time_to_reset(db, runner, timestamp).await;
-    db::update_runner_status(db, runner, db::RunnerStatus::IDLE).await;
+    db::update_runner_last_active(db, runner).await;
 
     println!("Launching runner {}", runner);
-    Status::Ok
+    Ok(Status::Ok)
+}
+
+
+/// Records the job the runner picked up, which also launches the runner unless its job
+/// started hook did so already
+pub async fn runner_job_started(
+    db: &mut SqliteConnection,
+    runner: &str,
+    job: RunnerJob,
+) -> Result<Status, transition::TransitionError> {
+    let value = format!("{} {} #{}", job.repository, job.name, job.run_id);
+    db::update_runner_job(db, runner, Some(&job)).await;
+    db::insert_history(
+        db,
+        db::HistoryKind::RUNNER,
+        runner,
+        db::HistoryEvent::JOB_STARTED,
+        Some(&value),
+        Some(runner),
+    )
+    .await;
+
+    if db::get_runner_info(db, runner).await.time_to_reset.is_none() {
+        return runner_launch(db, runner).await;
+    }
+    Ok(Status::Ok)
+}
+
+
+/// Resets the runner after its job, unless its job completed hook did so already
+pub async fn runner_job_completed(
+    db: &mut SqliteConnection,
+    runner: &str,
+    job: RunnerJob,
+) -> Result<Status, transition::TransitionError> {
+    let value = format!("{} {} #{}", job.repository, job.name, job.run_id);
+    db::update_runner_job(db, runner, None).await;
+    db::insert_history(
+        db,
+        db::HistoryKind::RUNNER,
+        runner,
+        db::HistoryEvent::JOB_COMPLETED,
+        Some(&value),
+        Some(runner),
+    )
+    .await;
+
+    if db::get_runner_status(db, runner).await == db::RunnerStatus::RESETTING {
+        return Ok(Status::Ok);
+    }
+    runner_reset(db, runner).await
+}
+
+
+async fn backend(db: &mut SqliteConnection, runner: &str) -> &'static dyn vm::VmBackend {
+    vm::backend(db::get_runner_backend(db, runner).await)
+}
+
+
+async fn vm_command_failed(
+    db: &mut SqliteConnection,
+    runner: &str,
+    command: vm::Command,
+    status: db::CommandStatus,
+    message: &str,
+) {
+    eprintln!("VM command {} failed for runner {}: {}", command.as_ref(), runner, message);
+    db::update_runner_last_command(db, runner, command, status, Some(message)).await;
+    // A snapshot or registration left behind does not affect the VM itself
+    if command != vm::Command::deletesnap && command != vm::Command::deregister {
+        db::update_runner_status(db, runner, db::RunnerStatus::ERROR)
+            .await
+            .unwrap_or_else(|e| eprintln!("{}", e));
+    }
+    let event = match command {
+        vm::Command::deregister => db::HistoryEvent::DEREGISTER_FAILED,
+        _ => db::HistoryEvent::VM_COMMAND_FAILED,
+    };
+    db::insert_history(
+        db,
+        db::HistoryKind::RUNNER,
+        runner,
+        event,
+        Some(&format!("{}: {}", command.as_ref(), message)),
+        Some(runner),
+    )
+    .await;
+}
+
+
+/// Queues the command, it is handed to the runner's backend by the VM command task
+/// once `VM_COMMAND_DELAY` passed
+async fn vm_command(
+    db: &mut SqliteConnection,
+    runner: &str,
+    command: vm::Command,
+    snapshot: Option<&str>,
+) {
+    let not_before = Utc::now().naive_utc() + chrono::Duration::seconds(*vm::COMMAND_DELAY);
+    db::insert_vm_command(db, runner, command, snapshot, not_before).await;
+    db::update_runner_last_command(db, runner, command, db::CommandStatus::PENDING, None).await;
+}
+
+
+/// Keeps the snapshots in line with the outcome of a createsnap command. A new snapshot
+/// becomes the current one and the oldest beyond `SNAPSHOT_RETENTION` are deleted.
+async fn settle_snapshot(
+    db: &mut SqliteConnection,
+    runner: &str,
+    command: vm::Command,
+    snapshot: Option<&str>,
+    done: bool,
+) {
+    if command != vm::Command::createsnap {
+        return;
+    }
+    if done {
+        db::update_runner_last_snapshot(db, runner, Utc::now().naive_utc()).await;
+    }
+
+    let Some(snapshot) = snapshot else {
+        return;
+    };
+    if !done {
+        db::delete_snapshot(db, runner, snapshot).await;
+        return;
+    }
+
+    db::update_snapshot_available(db, runner, snapshot).await;
+    db::update_runner_current_snapshot(db, runner, Some(snapshot)).await;
+
+    let outdated: Vec<String> = db::get_snapshots(db, runner)
+        .await
+        .into_iter()
+        .filter(|record| record.Available)
+        .skip(*vm::SNAPSHOT_RETENTION)
+        .map(|record| record.Name)
+        .collect();
+
+    for outdated in outdated {
+        println!("Deleting outdated snapshot {} of runner {}", outdated, runner);
+        db::delete_snapshot(db, runner, &outdated).await;
+        vm_command(db, runner, vm::Command::deletesnap, Some(&outdated)).await;
+    }
+}
+
+
+/// Hands a queued command to the backend, a failure is retried with exponential backoff
+/// until `VM_COMMAND_RETRIES` is reached and then puts the runner into ERROR
+async fn send_vm_command(
+    db: &mut SqliteConnection,
+    record: &db::VmCommandRecord,
+    command: vm::Command,
+) {
+    if record.NotBefore > Utc::now().naive_utc() {
+        return;
+    }
+
+    let runner = record.Runner.as_str();
+    let snapshot = record.Argument.as_deref();
+    let attempts = record.Attempts + 1;
+    let result = match command {
+        vm::Command::deregister => deregister(db, runner).await,
+        _ => backend(db, runner).await.exec(runner, command, snapshot).await,
+    };
+
+    match result {
+        // Nothing on the hypervisor side to wait for
+        Ok(()) if command == vm::Command::deregister => {
+            let status = db::VmCommandStatus::ACKNOWLEDGED;
+            db::update_vm_command(db, record.Id, status, attempts, record.NotBefore, None).await;
+        }
+        Ok(()) => {
+            let status = db::VmCommandStatus::SENT;
+            db::update_vm_command(db, record.Id, status, attempts, record.NotBefore, None).await;
+        }
+        Err(e) if attempts >= *vm::COMMAND_RETRIES => {
+            let message = e.to_string();
+            db::update_vm_command(
+                db,
+                record.Id,
+                db::VmCommandStatus::FAILED,
+                attempts,
+                record.NotBefore,
+                Some(&message),
+            )
+            .await;
+            vm_command_failed(db, runner, command, db::CommandStatus::FAILED, &message).await;
+            settle_snapshot(db, runner, command, snapshot, false).await;
+        }
+        Err(e) => {
+            let backoff = vm::RETRY_BACKOFF << (attempts - 1).min(10);
+            let not_before = Utc::now().naive_utc() + chrono::Duration::seconds(backoff);
+            eprintln!(
+                "VM command {} for runner {} failed, retrying in {}s: {}",
+                command.as_ref(),
+                runner,
+                backoff,
+                e
+            );
+            db::update_vm_command(
+                db,
+                record.Id,
+                db::VmCommandStatus::PENDING,
+                attempts,
+                not_before,
+                Some(&e.to_string()),
+            )
+            .await;
+        }
+    }
+}
+
+
+/// Settles a sent VM command once the backend acknowledged it or the timeout passed
+async fn check_vm_command(
+    db: &mut SqliteConnection,
+    record: &db::VmCommandRecord,
+    command: vm::Command,
+) {
+    let runner = record.Runner.as_str();
+    let snapshot = record.Argument.as_deref();
+    let acknowledgement = match backend(db, runner).await.acknowledgement(runner, command).await {
+        Ok(acknowledgement) => acknowledgement,
+        Err(e) => Some(vm::Acknowledgement::Failed(e.to_string())),
+    };
+
+    let (status, message) = match acknowledgement {
+        Some(vm::Acknowledgement::Done) => {
+            db::update_runner_last_command(db, runner, command, db::CommandStatus::DONE, None).await;
+            settle_snapshot(db, runner, command, snapshot, true).await;
+
+            // Reverted VMs stay RESETTING until they register, which makes them IDLE
+            let status = db::get_runner_info(db, runner).await.status;
+            match command {
+                // The VM idles where the snapshot was taken, booting it from the snapshot
+                // finishes the reset
+                vm::Command::createsnap if status == db::RunnerStatus::RESETTING => {
+                    let current = db::get_runner_current_snapshot(db, runner).await;
+                    vm_command(db, runner, vm::Command::revert, current.as_deref()).await;
+                }
+                vm::Command::stop if status != db::RunnerStatus::OFFLINE => {
+                    db::update_runner_status(db, runner, db::RunnerStatus::OFFLINE)
+                        .await
+                        .unwrap_or_else(|e| eprintln!("{}", e));
+                }
+                _ => {}
+            }
+            println!("VM command {} done for runner {}", command.as_ref(), runner);
+            (db::VmCommandStatus::ACKNOWLEDGED, None)
+        }
+        Some(vm::Acknowledgement::Failed(message)) => {
+            vm_command_failed(db, runner, command, db::CommandStatus::FAILED, &message).await;
+            settle_snapshot(db, runner, command, snapshot, false).await;
+            (db::VmCommandStatus::FAILED, Some(message))
+        }
+        None if record.UpdatedAt.and_utc().timestamp() + *vm::COMMAND_TIMEOUT
+            < Utc::now().timestamp() =>
+        {
+            let message = "Not acknowledged in time".to_string();
+            vm_command_failed(db, runner, command, db::CommandStatus::TIMEOUT, &message).await;
+            settle_snapshot(db, runner, command, snapshot, false).await;
+            (db::VmCommandStatus::FAILED, Some(message))
+        }
+        None => return,
+    };
+
+    let message = message.as_deref();
+    db::update_vm_command(db, record.Id, status, record.Attempts, record.NotBefore, message).await;
+}
+
+
+/// Compares the power state reported by the backend with the runner status. Runners whose
+/// VM stopped are marked OFFLINE, those whose VM crashed or runs unexpectedly ERROR.
+pub async fn reconcile_power_state(db: &mut SqliteConnection, runner: &str) {
+    let state = match backend(db, runner).await.status(runner).await {
+        Ok(state) => state,
+        Err(e) => {
+            eprintln!("Failed to query the power state of runner {}: {}", runner, e);
+            vm::PowerState::UNKNOWN
+        }
+    };
+    db::update_runner_power_state(db, runner, &state).await;
+
+    // Commands in flight change the power state on purpose
+    if state == vm::PowerState::UNKNOWN || db::has_unfinished_vm_command(db, runner).await {
+        return;
+    }
+
+    let status = db::get_runner_info(db, runner).await.status;
+    let reconciled = match (&state, &status) {
+        (vm::PowerState::CRASHED, db::RunnerStatus::ERROR) => None,
+        (vm::PowerState::CRASHED, _) => Some(db::RunnerStatus::ERROR),
+        (
+            vm::PowerState::STOPPED,
+            db::RunnerStatus::IDLE | db::RunnerStatus::RUNNING | db::RunnerStatus::RESETTING,
+        ) => Some(db::RunnerStatus::OFFLINE),
+        (vm::PowerState::RUNNING, db::RunnerStatus::OFFLINE) => Some(db::RunnerStatus::ERROR),
+        _ => None,
+    };
+
+    if let Some(reconciled) = reconciled {
+        println!(
+            "Runner {} is {} but its VM is {}, marking it {}",
+            runner,
+            status.as_ref(),
+            state.as_ref(),
+            reconciled.as_ref()
+        );
+        db::insert_history(
+            db,
+            db::HistoryKind::RUNNER,
+            runner,
+            db::HistoryEvent::POWER_STATE,
+            Some(state.as_ref()),
+            Some(runner),
+        )
+        .await;
+        db::update_runner_status(db, runner, reconciled)
+            .await
+            .unwrap_or_else(|e| eprintln!("{}", e));
+    }
+}
+
+
+/// Advances a command of the VM command queue, see `db::next_vm_commands`
+pub async fn process_vm_command(db: &mut SqliteConnection, record: db::VmCommandRecord) {
+    let command =
+        vm::Command::from_str(&record.Command).expect("Invalid Command: Database Corruption");
+    let status = db::VmCommandStatus::from_str(&record.Status)
+        .expect("Invalid VM Command Status: Database Corruption");
+
+    match status {
+        db::VmCommandStatus::PENDING => send_vm_command(db, &record, command).await,
+        db::VmCommandStatus::SENT => check_vm_command(db, &record, command).await,
+        _ => {}
+    }
 }
 
 
 async fn release_hardware(db: &mut SqliteConnection, runner: &str) {
+    // Leave all queues first, so released boards are not granted back to the runner
+    db::dequeue_runner(db, runner).await;
+
     let claimed_hw = db::get_hardware_claimed_by_runner(db, runner).await;
 
     for hardware in claimed_hw {
@@ -109,135 +649,400 @@ async fn release_hardware(db: &mut SqliteConnection, runner: &str) {
 }
 
 
-pub async fn runner_reset(db: &mut SqliteConnection, runner: &str) -> Status {
+pub async fn runner_reset(
+    db: &mut SqliteConnection,
+    runner: &str,
+) -> Result<Status, transition::TransitionError> {
     if !db::runner_exists(db, runner).await {
         eprintln!("Runner does not exist");
-        return Status::NotFound;
+        return Ok(Status::NotFound);
     }
 
+    db::update_runner_status(db, runner, db::RunnerStatus::RESETTING).await?;
     let timestamp = None;
     db::update_runner_time_to_reset(db, runner, timestamp).await;
-    db::update_runner_status(db, runner, db::RunnerStatus::RESETTING).await;
 
     release_hardware(db, runner).await; // release all hardware claimed by runner
-    vm::reset(runner).await;
+
+    // Just-in-time runners are removed by GitHub after their job
+    if !db::get_runner_jit(db, runner).await {
+        vm_command(db, runner, vm::Command::deregister, None).await;
+    }
+    let snapshot = db::get_runner_current_snapshot(db, runner).await;
+    vm_command(db, runner, vm::Command::revert, snapshot.as_deref()).await;
 
     println!("Resetting runner {}", runner);
-    Status::Ok
+    Ok(Status::Ok)
 }
 
 
-async fn fetch_github_token(
-    org: &str,
-    pat: &str,
-    proxy: &str,
-) -> Result<TokenResponse, reqwest::Error> {
-    println!("Beginning request");
+/// Organization or repository the runner registers with, its own or the configured default
+async fn runner_scope(db: &mut SqliteConnection, runner: &str) -> Option<github::Scope> {
+    db::get_runner_scope(db, runner).await.or(github::default_scope())
+}
 
-    let client = Client::builder()
-        .proxy(Proxy::https(proxy)?)
-        .build()?;
 
-    let url = format!(
-        "https://api.github.com/orgs/{}/actions/runners/registration-token",
-        org
-    );
-    let response = client
-        .post(&url)
-        .header("Authorization", format!("Bearer {}", pat))
-        .header("Accept", "application/vnd.github.v3+json")
-        .header(header::USER_AGENT, env!("CARGO_PKG_NAME"))
-        .send()
-        .await?
-        .json::<TokenResponse>()
-        .await?;
+/// The registration on GitHub does not survive the revert. Runs from the VM command queue,
+/// so a slow or failing GitHub does not hold up the reset.
+async fn deregister(db: &mut SqliteConnection, runner: &str) -> anyhow::Result<()> {
+    let Some(scope) = runner_scope(db, runner).await else {
+        eprintln!("No GitHub scope configured for runner {}", runner);
+        return Ok(());
+    };
 
-    Ok(response)
+    if github::deregister_runner(&scope, runner).await? {
+        println!("Removed runner {} from GitHub", runner);
+    }
+    Ok(())
 }
 
 
-pub async fn runner_return_github_token(
-    mut db: Connection<db::RunnerDb>,
+/// Registering makes the runner IDLE, which is refused while a VM command like the revert
+/// of a reset is still outstanding, as the VM is about to change under the runner
+async fn check_registration(
+    db: &mut SqliteConnection,
     runner: &str,
-) -> Result<String, Status> {
-    dotenv::dotenv().ok();
-    let org = env::var("GITHUB_ORG").ok();
-    let pat = env::var("GITHUB_PAT").ok();
-    let proxy = env::var("PROXY_URL").ok();
-
-    if org.is_none() || pat.is_none() || proxy.is_none() {
-        eprintln!("Missing required environment variables");
-        return Err(Status::InternalServerError);
+) -> Result<github::Scope, status::Custom<String>> {
+    if !db::runner_exists(db, runner).await {
+        eprintln!("Runner not found in database");
+        return Err(status::Custom(Status::BadRequest, String::new()));
     }
 
-    if !db::runner_exists(&mut db, runner).await {
-        eprintln!("Runner not found in database");
-        return Err(Status::BadRequest);
+    let Some(scope) = runner_scope(db, runner).await else {
+        eprintln!("No GitHub scope configured for runner {}", runner);
+        return Err(status::Custom(Status::InternalServerError, String::new()));
+    };
+
+    let current = db::get_runner_status(db, runner).await;
+    if let Err(e) = transition::check_runner(runner, &current, &db::RunnerStatus::IDLE) {
+        eprintln!("{}", e);
+        return Err(status::Custom(Status::Conflict, e.to_string()));
     }
+    if db::has_unfinished_vm_command(db, runner).await {
+        let reason = format!("Runner {} has VM commands outstanding", runner);
+        eprintln!("{}", reason);
+        return Err(status::Custom(Status::Conflict, reason));
+    }
+
+    Ok(scope)
+}
 
-    let token = fetch_github_token(&org.unwrap(), &pat.unwrap(), &proxy.unwrap()).await;
 
-    match token {
-        Ok(token) => {
-            db::update_runner_status(&mut db, runner, db::RunnerStatus::IDLE).await;
-            Ok(token.token)
+/// Hands out what the runner registers with and makes it IDLE. A GitHub failure leaves the
+/// status alone, the VM simply asks again.
+async fn registered(
+    db: &mut SqliteConnection,
+    runner: &str,
+    credential: anyhow::Result<String>,
+    jit: bool,
+) -> Result<String, status::Custom<String>> {
+    match credential {
+        Ok(credential) => {
+            db::update_runner_status(db, runner, db::RunnerStatus::IDLE)
+                .await
+                .map_err(|e| status::Custom(Status::Conflict, e.to_string()))?;
+            db::update_runner_last_active(db, runner).await;
+            db::update_runner_jit(db, runner, jit).await;
+            Ok(credential)
         }
-        Err(_) => {
-            db::update_runner_status(&mut db, runner, db::RunnerStatus::ERROR).await;
-            Err(Status::InternalServerError)
+        Err(e) => {
+            eprintln!("Failed to register runner {} with GitHub: {:#}", runner, e);
+            Err(status::Custom(Status::InternalServerError, String::new()))
         }
     }
 }
 
 
-pub async fn vm_snapshot(mut db: Connection<db::RunnerDb>, runner: &str) -> Status {
-    if !db::runner_exists(&mut db, runner).await {
+pub async fn runner_return_registration(
+    mut db: Connection<db::RunnerDb>,
+    runner: &str,
+) -> Result<Registration, status::Custom<String>> {
+    let scope = check_registration(&mut db, runner).await?;
+    let token = github::registration_token(&scope).await;
+    let token = registered(&mut db, runner, token, false).await?;
+    Ok(Registration { url: scope.url(), token })
+}
+
+
+/// Token only, kept for VMs which still take the URL from their own configuration
+pub async fn runner_return_github_token(
+    db: Connection<db::RunnerDb>,
+    runner: &str,
+) -> Result<String, status::Custom<String>> {
+    Ok(runner_return_registration(db, runner).await?.token)
+}
+
+
+/// Encoded config for `run.sh --jitconfig`, GitHub removes the runner after one job
+pub async fn runner_return_jit_config(
+    mut db: Connection<db::RunnerDb>,
+    runner: &str,
+) -> Result<String, status::Custom<String>> {
+    let scope = check_registration(&mut db, runner).await?;
+    let labels = db::get_runner_labels(&mut db, runner).await.unwrap_or(github::default_labels());
+    let group = db::get_runner_group(&mut db, runner).await.unwrap_or(*github::RUNNER_GROUP);
+
+    let config = github::jit_config(&scope, runner, &labels, group).await;
+    registered(&mut db, runner, config, true).await
+}
+
+
+pub async fn vm_snapshot(
+    db: &mut SqliteConnection,
+    runner: &str,
+    snapshot: NewSnapshot,
+) -> Result<Status, transition::TransitionError> {
+    if !db::runner_exists(db, runner).await {
         eprintln!("Runner does not exist");
-        return Status::NotFound;
+        return Ok(Status::NotFound);
     }
 
-    let runner = runner.to_string(); // required due to spawn closure
+    let name = snapshot
+        .name
+        .unwrap_or_else(|| format!("{}-{}", runner, Utc::now().format("%Y%m%d%H%M%S")));
+
+    if !vm::valid_name(&name) {
+        eprintln!("Invalid snapshot name {}", name);
+        return Ok(Status::BadRequest);
+    }
 
-    rocket::tokio::spawn(async move {
-        db::update_runner_status(&mut db, &runner, db::RunnerStatus::RESETTING).await;
+    if db::get_snapshot(db, runner, &name).await.is_some() {
+        eprintln!("Snapshot {} already exists", name);
+        return Ok(Status::Conflict);
+    }
 
-        let timestamp = None;
-        db::update_runner_time_to_reset(&mut db, &runner, timestamp).await;
+    db::update_runner_status(db, runner, db::RunnerStatus::RESETTING).await?;
 
-        release_hardware(&mut db, &runner).await; // release all hardware claimed by runner
+    let timestamp = None;
+    db::update_runner_time_to_reset(db, runner, timestamp).await;
 
+    release_hardware(db, runner).await; // release all hardware claimed by runner
 
-        vm::snapshot(&runner).await;
-        println!("Snapshotting runner {}", runner);
-    });
+    db::insert_snapshot(db, runner, &name, snapshot.base_image.as_deref()).await;
+    vm_command(db, runner, vm::Command::createsnap, Some(&name)).await;
+    println!("Snapshotting runner {} as {}", runner, name);
 
-    return Status::Ok;
+    Ok(Status::Ok)
 }
 
 
-pub async fn vm_start(db: &mut SqliteConnection, runner: &str) -> Status {
+/// The runner is RESETTING until the started VM registers again
+pub async fn vm_start(
+    db: &mut SqliteConnection,
+    runner: &str,
+) -> Result<Status, transition::TransitionError> {
     if !db::runner_exists(db, runner).await {
         eprintln!("Runner does not exist");
-        return Status::NotFound;
+        return Ok(Status::NotFound);
     }
 
-    db::update_runner_status(db, runner, db::RunnerStatus::RUNNING).await;
-    vm::start(runner).await;
+    db::update_runner_status(db, runner, db::RunnerStatus::RESETTING).await?;
+    vm_command(db, runner, vm::Command::start, None).await;
     println!("Starting runner vm {}", runner);
 
-    Status::Ok
+    Ok(Status::Ok)
 }
 
 
-pub async fn vm_stop(db: &mut SqliteConnection, runner: &str) -> Status {
+pub async fn vm_stop(
+    db: &mut SqliteConnection,
+    runner: &str,
+) -> Result<Status, transition::TransitionError> {
     if !db::runner_exists(db, runner).await {
         eprintln!("Runner does not exist");
-        return Status::NotFound;
+        return Ok(Status::NotFound);
     }
 
-    db::update_runner_status(db, runner, db::RunnerStatus::OFFLINE).await;
-    vm::stop(runner).await;
+    db::update_runner_status(db, runner, db::RunnerStatus::OFFLINE).await?;
+    vm_command(db, runner, vm::Command::stop, None).await;
     println!("Stopping runner vm {}", runner);
 
-    Status::Ok
-}
\ No newline at end of file
+    Ok(Status::Ok)
+}
+
+
+pub async fn vm_commands(
+    db: &mut SqliteConnection,
+    runner: &str,
+) -> Result<Vec<vm::QueuedCommand>, Status> {
+    if !db::runner_exists(db, runner).await {
+        eprintln!("Runner does not exist");
+        return Err(Status::NotFound);
+    }
+
+    Ok(db::get_vm_commands(db, runner)
+        .await
+        .into_iter()
+        .map(vm::QueuedCommand::from)
+        .collect())
+}
+
+
+pub async fn vm_snapshots(
+    db: &mut SqliteConnection,
+    runner: &str,
+) -> Result<Vec<SnapshotInfo>, Status> {
+    if !db::runner_exists(db, runner).await {
+        eprintln!("Runner does not exist");
+        return Err(Status::NotFound);
+    }
+
+    let current = db::get_runner_current_snapshot(db, runner).await;
+    Ok(db::get_snapshots(db, runner)
+        .await
+        .into_iter()
+        .map(|record| SnapshotInfo::from(record, current.as_deref()))
+        .collect())
+}
+
+
+/// Resets runners which waited for a job longer than `IDLE_RESET_AFTER`, so they pick up
+/// updates
+pub async fn reset_if_idle(db: &mut SqliteConnection, runner: &RunnerInfo) {
+    let Some(idle_reset_at) = &runner.idle_reset_at else {
+        return;
+    };
+    if idle_reset_at.unix() > Utc::now().timestamp() {
+        return;
+    }
+
+    if let Err(e) = runner_reset(db, &runner.name).await {
+        eprintln!("{}", e);
+        return;
+    }
+    db::insert_history(
+        db,
+        db::HistoryKind::RUNNER,
+        &runner.name,
+        db::HistoryEvent::IDLE_RESET,
+        None,
+        Some(&runner.name),
+    )
+    .await;
+    println!("Idle reset of {}", runner.name);
+}
+
+
+/// Asked by the first stage of a VM right after its update, before it registers. Takes a
+/// new snapshot once the last one is older than `SNAPSHOT_MAX_AGE`, which spares fresh VMs
+/// most of their update work. Answers 202 if the VM is to idle for the snapshot, 204 if it
+/// can go on.
+pub async fn vm_snapshot_if_due(
+    db: &mut SqliteConnection,
+    runner: &str,
+) -> Result<Status, transition::TransitionError> {
+    if !db::runner_exists(db, runner).await {
+        eprintln!("Runner does not exist");
+        return Ok(Status::NotFound);
+    }
+
+    let last_snapshot = db::get_runner_last_snapshot(db, runner).await;
+    let due = last_snapshot.map_or(true, |last_snapshot| {
+        last_snapshot.and_utc().timestamp() + *vm::SNAPSHOT_MAX_AGE <= Utc::now().timestamp()
+    });
+    if !due {
+        return Ok(Status::NoContent);
+    }
+
+    println!("Snapshot of runner {} is outdated", runner);
+    match vm_snapshot(db, runner, NewSnapshot::default()).await? {
+        Status::Ok => Ok(Status::Accepted),
+        status => Ok(status),
+    }
+}
+
+
+/// Makes the snapshot the current one and resets the runner to it, later resets keep
+/// going back to it until a new snapshot is taken
+pub async fn vm_revert(
+    db: &mut SqliteConnection,
+    runner: &str,
+    snapshot: &str,
+) -> Result<Status, transition::TransitionError> {
+    if !db::runner_exists(db, runner).await {
+        eprintln!("Runner does not exist");
+        return Ok(Status::NotFound);
+    }
+
+    match db::get_snapshot(db, runner, snapshot).await {
+        Some(record) if record.Available => {}
+        Some(_) => {
+            eprintln!("Snapshot {} is not taken yet", snapshot);
+            return Ok(Status::Conflict);
+        }
+        None => {
+            eprintln!("Snapshot {} does not exist", snapshot);
+            return Ok(Status::NotFound);
+        }
+    }
+
+    let previous = db::get_runner_current_snapshot(db, runner).await;
+    db::update_runner_current_snapshot(db, runner, Some(snapshot)).await;
+    let result = runner_reset(db, runner).await;
+    if result.is_err() {
+        db::update_runner_current_snapshot(db, runner, previous.as_deref()).await;
+    }
+    result
+}
+
+
+
+//------------------------------------------------------------------------------
+// Tests
+//------------------------------------------------------------------------------
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RUNNER: &str = "runner-1";
+
+    fn job() -> RunnerJob {
+        RunnerJob {
+            id: 1,
+            run_id: 2,
+            name: "build".to_string(),
+            repository: "org/repo".to_string(),
+            url: None,
+        }
+    }
+
+
+    #[rocket::async_test]
+    async fn webhook_then_hook() {
+        let mut db = db::test_db().await;
+        db::insert_runner(&mut db, RUNNER, db::RunnerStatus::IDLE).await;
+
+        assert_eq!(runner_job_started(&mut db, RUNNER, job()).await.unwrap(), Status::Ok);
+        assert_eq!(runner_launch(&mut db, RUNNER).await.unwrap(), Status::Ok);
+
+        let info = db::get_runner_info(&mut db, RUNNER).await;
+        assert_eq!(info.status, db::RunnerStatus::RUNNING);
+        assert_eq!(info.job, Some(job()));
+    }
+
+
+    #[rocket::async_test]
+    async fn hook_then_webhook() {
+        let mut db = db::test_db().await;
+        db::insert_runner(&mut db, RUNNER, db::RunnerStatus::IDLE).await;
+
+        assert_eq!(runner_launch(&mut db, RUNNER).await.unwrap(), Status::Ok);
+        assert_eq!(runner_job_started(&mut db, RUNNER, job()).await.unwrap(), Status::Ok);
+
+        let info = db::get_runner_info(&mut db, RUNNER).await;
+        assert_eq!(info.status, db::RunnerStatus::RUNNING);
+        assert_eq!(info.job, Some(job()));
+    }
+
+
+    #[rocket::async_test]
+    async fn launch_twice_without_job() {
+        let mut db = db::test_db().await;
+        db::insert_runner(&mut db, RUNNER, db::RunnerStatus::IDLE).await;
+
+        assert_eq!(runner_launch(&mut db, RUNNER).await.unwrap(), Status::Ok);
+        assert_eq!(runner_launch(&mut db, RUNNER).await.unwrap(), Status::Conflict);
+    }
+}
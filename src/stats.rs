@@ -0,0 +1,368 @@
+//
+// Copyright (C) 2024, HENSOLDT Cyber GmbH
+// 
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// For commercial licensing, contact: info.cyber@hensoldt.net
+//
+
+
+use chrono::{DateTime, Utc};
+use rocket::http::{ContentType, Status};
+use rocket::serde::{json, Deserialize, Serialize};
+use rocket_db_pools::sqlx::SqliteConnection;
+use rocket_okapi::{
+    gen::OpenApiGenerator,
+    okapi::{openapi3::Responses, schemars, schemars::JsonSchema},
+    response::OpenApiResponderInner,
+    util::add_schema_response,
+};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{db, timestamp};
+
+
+
+//------------------------------------------------------------------------------
+// Config
+//------------------------------------------------------------------------------
+
+
+/// Window used when a report does not specify where it starts
+const DEFAULT_WINDOW: i64 = 30 * 24 * 60 * 60; // 30 days
+
+
+
+//------------------------------------------------------------------------------
+// Data Structures
+//------------------------------------------------------------------------------
+
+
+#[derive(Debug, Clone, Copy, FromFormField, JsonSchema)]
+#[allow(non_camel_case_types)]
+pub enum ReportFormat {
+    json,
+    csv,
+}
+
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct HardwareStats {
+    pub hardware: String,
+    pub board_type: Option<String>,
+    pub claims: i64,
+    pub busy_seconds: i64,
+    pub utilization: f64,
+    pub average_wait_seconds: Option<f64>,
+}
+
+impl HardwareStats {
+    fn new(hardware: String, board_type: Option<String>) -> Self {
+        Self {
+            hardware,
+            board_type,
+            claims: 0,
+            busy_seconds: 0,
+            utilization: 0.0,
+            average_wait_seconds: None,
+        }
+    }
+}
+
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RunnerStats {
+    pub runner: String,
+    pub resets: i64,
+    pub force_resets: i64,
+    pub errors: i64,
+    pub busy_seconds: i64,
+}
+
+impl RunnerStats {
+    fn new(runner: String) -> Self {
+        Self {
+            runner,
+            resets: 0,
+            force_resets: 0,
+            errors: 0,
+            busy_seconds: 0,
+        }
+    }
+}
+
+
+/// Statistics rendered as JSON or CSV
+#[derive(Responder)]
+pub struct Report {
+    body: String,
+    content_type: ContentType,
+}
+
+impl OpenApiResponderInner for Report {
+    fn responses(gen: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses> {
+        let mut responses = Responses::default();
+        let schema = gen.json_schema::<String>();
+        add_schema_response(&mut responses, 200, "application/json", schema.clone())?;
+        add_schema_response(&mut responses, 200, "text/csv", schema)?;
+        Ok(responses)
+    }
+}
+
+
+trait CsvRecord {
+    const HEADER: &'static str;
+    fn row(&self) -> String;
+}
+
+impl CsvRecord for HardwareStats {
+    const HEADER: &'static str =
+        "hardware,board_type,claims,busy_seconds,utilization,average_wait_seconds";
+
+    fn row(&self) -> String {
+        format!(
+            "{},{},{},{},{:.4},{}",
+            csv_field(&self.hardware),
+            csv_field(self.board_type.as_deref().unwrap_or("")),
+            self.claims,
+            self.busy_seconds,
+            self.utilization,
+            self.average_wait_seconds.map(|w| format!("{:.1}", w)).unwrap_or_default(),
+        )
+    }
+}
+
+impl CsvRecord for RunnerStats {
+    const HEADER: &'static str = "runner,resets,force_resets,errors,busy_seconds";
+
+    fn row(&self) -> String {
+        format!(
+            "{},{},{},{},{}",
+            csv_field(&self.runner),
+            self.resets,
+            self.force_resets,
+            self.errors,
+            self.busy_seconds,
+        )
+    }
+}
+
+
+/// Status of one subject while replaying the history
+struct Tracker {
+    status: String,
+    since: i64,
+}
+
+
+
+//------------------------------------------------------------------------------
+// Utility Functions
+//------------------------------------------------------------------------------
+
+
+fn render<T: Serialize + CsvRecord>(rows: Vec<T>, format: ReportFormat) -> Result<Report, Status> {
+    match format {
+        ReportFormat::json => Ok(Report {
+            body: json::to_string(&rows).map_err(|_| Status::InternalServerError)?,
+            content_type: ContentType::JSON,
+        }),
+        ReportFormat::csv => {
+            let mut body = String::from(T::HEADER);
+            for row in rows {
+                body.push('\n');
+                body.push_str(&row.row());
+            }
+            body.push('\n');
+            Ok(Report {
+                body,
+                content_type: ContentType::CSV,
+            })
+        }
+    }
+}
+
+
+/// Resolves the requested window of representable dates, defaulting to the last 30 days
+fn window(from: Option<i64>, to: Option<i64>) -> Result<(i64, i64), Status> {
+    let representable = |t: i64| DateTime::from_timestamp(t, 0).is_some();
+
+    let to = to.unwrap_or(Utc::now().timestamp()).min(Utc::now().timestamp());
+    let from = match from {
+        Some(from) => from,
+        None => to.checked_sub(DEFAULT_WINDOW).ok_or(Status::BadRequest)?,
+    };
+    if !representable(from) || !representable(to) || from >= to {
+        return Err(Status::BadRequest);
+    }
+    Ok((from, to))
+}
+
+
+/// Seconds of [start, end] that fall into [from, to]
+fn overlap(start: i64, end: i64, from: i64, to: i64) -> i64 {
+    (end.min(to) - start.max(from)).max(0)
+}
+
+
+async fn history_window(
+    db: &mut SqliteConnection,
+    kind: db::HistoryKind,
+    from: i64,
+    to: i64,
+) -> Vec<db::HistoryRecord> {
+    let date = |t: i64| timestamp::Timestamp::from_unix(t).chrono().expect("Invalid timestamp");
+    db::get_history_window(db, kind, date(from), date(to)).await
+}
+
+
+/// Quoted as of RFC 4180 if the field contains a separator, quote or line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+
+
+//------------------------------------------------------------------------------
+// Stats Endpoint Logic
+//------------------------------------------------------------------------------
+
+
+pub async fn hardware_stats(
+    db: &mut SqliteConnection,
+    from: Option<i64>,
+    to: Option<i64>,
+    format: ReportFormat,
+) -> Result<Report, Status> {
+    let (from, to) = window(from, to)?;
+
+    // Every current board is reported, even without any claims
+    let mut stats: BTreeMap<String, HardwareStats> = BTreeMap::new();
+    for hardware in db::hardware_board_list(db).await {
+        let board_type = db::get_hardware_info(db, &hardware).await.board_type;
+        stats.insert(hardware.clone(), HardwareStats::new(hardware, board_type));
+    }
+
+    let mut trackers: HashMap<String, Tracker> = HashMap::new();
+    let mut queued: HashMap<(String, String), i64> = HashMap::new();
+    let mut waits: HashMap<String, Vec<i64>> = HashMap::new();
+
+    for record in history_window(db, db::HistoryKind::HARDWARE, from, to).await {
+        let time = record.Time.and_utc().timestamp();
+        let entry = stats
+            .entry(record.Subject.clone())
+            .or_insert_with(|| HardwareStats::new(record.Subject.clone(), None));
+
+        match record.Event.as_str() {
+            "QUEUED" => {
+                if let Some(runner) = record.Runner {
+                    queued.insert((record.Subject, runner), time);
+                }
+            }
+            "STATUS" => {
+                let status = record.Value.unwrap_or_default();
+
+                if let Some(tracker) = trackers.get(&record.Subject) {
+                    if tracker.status == db::HardwareStatus::CLAIMED.as_ref() {
+                        entry.busy_seconds += overlap(tracker.since, time, from, to);
+                    }
+                }
+
+                if status == db::HardwareStatus::CLAIMED.as_ref() && time >= from {
+                    entry.claims += 1;
+
+                    // Claims without a queue entry were granted right away
+                    let key = (record.Subject.clone(), record.Runner.unwrap_or_default());
+                    let wait = queued.remove(&key).map_or(0, |enqueued| time - enqueued);
+                    waits.entry(record.Subject.clone()).or_default().push(wait);
+                }
+
+                trackers.insert(record.Subject, Tracker { status, since: time });
+            }
+            _ => {}
+        }
+    }
+
+    // Boards still claimed at the end of the window
+    for (hardware, tracker) in trackers {
+        if tracker.status == db::HardwareStatus::CLAIMED.as_ref() {
+            if let Some(entry) = stats.get_mut(&hardware) {
+                entry.busy_seconds += overlap(tracker.since, to, from, to);
+            }
+        }
+    }
+
+    for entry in stats.values_mut() {
+        entry.utilization = entry.busy_seconds as f64 / (to - from) as f64;
+        entry.average_wait_seconds = waits
+            .get(&entry.hardware)
+            .map(|w| w.iter().sum::<i64>() as f64 / w.len() as f64);
+    }
+
+    render(stats.into_values().collect(), format)
+}
+
+
+pub async fn runner_stats(
+    db: &mut SqliteConnection,
+    from: Option<i64>,
+    to: Option<i64>,
+    format: ReportFormat,
+) -> Result<Report, Status> {
+    let (from, to) = window(from, to)?;
+
+    let mut stats: BTreeMap<String, RunnerStats> = BTreeMap::new();
+    for runner in db::runner_id_list(db).await {
+        stats.insert(runner.clone(), RunnerStats::new(runner));
+    }
+
+    let mut trackers: HashMap<String, Tracker> = HashMap::new();
+
+    for record in history_window(db, db::HistoryKind::RUNNER, from, to).await {
+        let time = record.Time.and_utc().timestamp();
+        let entry = stats
+            .entry(record.Subject.clone())
+            .or_insert_with(|| RunnerStats::new(record.Subject.clone()));
+
+        match record.Event.as_str() {
+            "FORCE_RESET" if time >= from => entry.force_resets += 1,
+            "STATUS" => {
+                let status = record.Value.unwrap_or_default();
+                let previous = trackers.get(&record.Subject);
+
+                if let Some(tracker) = previous {
+                    if tracker.status == db::RunnerStatus::RUNNING.as_ref() {
+                        entry.busy_seconds += overlap(tracker.since, time, from, to);
+                    }
+                }
+
+                // Repeated statuses are recorded as well, only changes count
+                if time >= from && previous.is_some_and(|tracker| tracker.status != status) {
+                    if status == db::RunnerStatus::RESETTING.as_ref() {
+                        entry.resets += 1;
+                    } else if status == db::RunnerStatus::ERROR.as_ref() {
+                        entry.errors += 1;
+                    }
+                }
+
+                trackers.insert(record.Subject, Tracker { status, since: time });
+            }
+            _ => {}
+        }
+    }
+
+    for (runner, tracker) in trackers {
+        if tracker.status == db::RunnerStatus::RUNNING.as_ref() {
+            if let Some(entry) = stats.get_mut(&runner) {
+                entry.busy_seconds += overlap(tracker.since, to, from, to);
+            }
+        }
+    }
+
+    render(stats.into_values().collect(), format)
+}
+
@@ -18,25 +18,32 @@ use rocket_okapi::okapi::{schemars, schemars::JsonSchema};
 //------------------------------------------------------------------------------
 
 
-static VALIDITY: LazyLock<i64> = LazyLock::new(|| {
+/// Time a runner may work on a job before it is force reset
+pub static VALIDITY: LazyLock<i64> = LazyLock::new(|| {
     let input = env::var("RUNNER_VALIDITY").unwrap_or("60min".to_string());
-    let validity = 60 * 60; // Default value 60 Minutes
-    
-    let ending = &input[input.len() - 3..];
-    if let Ok(value) = &input[..input.len() - 3].parse::<i64>() {
-        match ending {
-            "sec" => *value,
-            "min" => *value * 60,
-            "hrs" => *value * 60 * 60,
-            "day" => *value * 60 * 60 * 24,
-            _ => validity,
-        }
-    } else {
-        validity
-    }
+    parse_duration(&input).unwrap_or(60 * 60) // Default value 60 Minutes
 });
 
 
+/// Parses durations of the form `<value><unit>` into seconds, where unit is one of
+/// sec, min, hrs or day (e.g. "60min"). Only positive durations are accepted.
+pub fn parse_duration(input: &str) -> Option<i64> {
+    if input.len() < 4 || !input.is_char_boundary(input.len() - 3) {
+        return None;
+    }
+
+    let (value, ending) = input.split_at(input.len() - 3);
+    let value = value.parse::<i64>().ok().filter(|value| *value > 0)?;
+    match ending {
+        "sec" => Some(value),
+        "min" => value.checked_mul(60),
+        "hrs" => value.checked_mul(60 * 60),
+        "day" => value.checked_mul(60 * 60 * 24),
+        _ => None,
+    }
+}
+
+
 
 //------------------------------------------------------------------------------
 // Data Structures
@@ -52,11 +59,11 @@ pub struct Timestamp {
 
 impl Timestamp {
     pub fn new() -> Self {
-        let timestamp = Utc::now().timestamp() + *VALIDITY;
-        Self {
-            timestamp,
-            time_str: DateTime::from_timestamp(timestamp, 0).expect("Invalid timestamp").to_rfc3339(),
-        }
+        Self::from_now(*VALIDITY)
+    }
+
+    pub fn from_now(seconds: i64) -> Self {
+        Self::from_unix(Utc::now().timestamp() + seconds)
     }
 
     pub fn from(stmp: chrono::NaiveDateTime) -> Self {
@@ -90,3 +97,47 @@ impl Timestamp {
         }
     }
 }
+
+
+
+//------------------------------------------------------------------------------
+// Tests
+//------------------------------------------------------------------------------
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("45sec"), Some(45));
+        assert_eq!(parse_duration("60min"), Some(60 * 60));
+        assert_eq!(parse_duration("2hrs"), Some(2 * 60 * 60));
+        assert_eq!(parse_duration("30day"), Some(30 * 24 * 60 * 60));
+    }
+
+
+    #[test]
+    fn malformed_durations() {
+        for input in ["", "min", "60", "60 min", "60mins", "60MIN", "1.5hrs", "sixtymin", "€a"] {
+            assert_eq!(parse_duration(input), None, "{}", input);
+        }
+    }
+
+
+    #[test]
+    fn non_positive_durations() {
+        for input in ["0sec", "0day", "-1sec", "-60min"] {
+            assert_eq!(parse_duration(input), None, "{}", input);
+        }
+    }
+
+
+    #[test]
+    fn overflowing_durations() {
+        assert_eq!(parse_duration(&format!("{}sec", i64::MAX)), Some(i64::MAX));
+        assert_eq!(parse_duration(&format!("{}min", i64::MAX)), None);
+        assert_eq!(parse_duration(&format!("{}day", i64::MAX / 60)), None);
+    }
+}
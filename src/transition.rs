@@ -0,0 +1,198 @@
+//
+// Copyright (C) 2024, HENSOLDT Cyber GmbH
+// 
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// For commercial licensing, contact: info.cyber@hensoldt.net
+//
+
+
+use rocket_okapi::{
+    gen::OpenApiGenerator,
+    okapi::openapi3::Responses,
+    response::OpenApiResponderInner,
+    util::add_schema_response,
+};
+use std::fmt;
+
+use crate::db::{HardwareStatus, RunnerStatus};
+
+
+
+//------------------------------------------------------------------------------
+// Data Structures
+//------------------------------------------------------------------------------
+
+
+/// Rejected status change, answered with 409 and the reason
+#[derive(Debug, Responder)]
+#[response(status = 409, content_type = "plain")]
+pub struct TransitionError(String);
+
+impl TransitionError {
+    fn new(subject: &str, from: &str, to: &str) -> Self {
+        Self(format!("{} can not change from {} to {}", subject, from, to))
+    }
+}
+
+impl fmt::Display for TransitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for TransitionError {}
+
+impl OpenApiResponderInner for TransitionError {
+    fn responses(gen: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses> {
+        let mut responses = Responses::default();
+        add_schema_response(&mut responses, 409, "text/plain", gen.json_schema::<String>())?;
+        Ok(responses)
+    }
+}
+
+
+
+//------------------------------------------------------------------------------
+// Transition Tables
+//------------------------------------------------------------------------------
+
+
+/// A runner is RESETTING until its VM is back and registers, which makes it IDLE. Only
+/// idle runners take jobs. Stopped VMs are OFFLINE and come back through RESETTING.
+fn runner_allows(from: &RunnerStatus, to: &RunnerStatus) -> bool {
+    use RunnerStatus::*;
+
+    from == to
+        || matches!(
+            (from, to),
+            (RESETTING, IDLE | ERROR | OFFLINE)
+                | (IDLE, RUNNING | RESETTING | ERROR | OFFLINE)
+                | (RUNNING, RESETTING | ERROR | OFFLINE)
+                | (ERROR, RESETTING | OFFLINE)
+                | (OFFLINE, RESETTING | ERROR)
+        )
+}
+
+
+/// Only free boards can be claimed, a claim is never handed over directly
+fn hardware_allows(from: &HardwareStatus, to: &HardwareStatus) -> bool {
+    use HardwareStatus::*;
+
+    matches!(
+        (from, to),
+        (FREE, FREE | CLAIMED | UNAVAILABLE | ERROR)
+            | (CLAIMED, FREE | UNAVAILABLE | ERROR)
+            | (UNAVAILABLE, FREE | UNAVAILABLE | ERROR)
+            | (ERROR, FREE | UNAVAILABLE | ERROR)
+    )
+}
+
+
+pub fn check_runner(
+    runner: &str,
+    from: &RunnerStatus,
+    to: &RunnerStatus,
+) -> Result<(), TransitionError> {
+    if !runner_allows(from, to) {
+        let subject = format!("Runner {}", runner);
+        return Err(TransitionError::new(&subject, from.as_ref(), to.as_ref()));
+    }
+    Ok(())
+}
+
+
+pub fn check_hardware(
+    hardware: &str,
+    from: &HardwareStatus,
+    to: &HardwareStatus,
+) -> Result<(), TransitionError> {
+    if !hardware_allows(from, to) {
+        let subject = format!("Hardware {}", hardware);
+        return Err(TransitionError::new(&subject, from.as_ref(), to.as_ref()));
+    }
+    Ok(())
+}
+
+
+
+//------------------------------------------------------------------------------
+// Tests
+//------------------------------------------------------------------------------
+
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    const RUNNER_STATUSES: [&str; 5] = ["RESETTING", "IDLE", "RUNNING", "ERROR", "OFFLINE"];
+    const HARDWARE_STATUSES: [&str; 4] = ["FREE", "CLAIMED", "UNAVAILABLE", "ERROR"];
+
+    /// Rows are the current status, columns the new one, both in the order above
+    const RUNNER_TABLE: [[bool; 5]; 5] = [
+        [true, true, false, true, true],   // RESETTING
+        [true, true, true, true, true],    // IDLE
+        [true, false, true, true, true],   // RUNNING
+        [true, false, false, true, true],  // ERROR
+        [true, false, false, true, true],  // OFFLINE
+    ];
+
+    const HARDWARE_TABLE: [[bool; 4]; 4] = [
+        [true, true, true, true],   // FREE
+        [true, false, true, true],  // CLAIMED
+        [true, false, true, true],  // UNAVAILABLE
+        [true, false, true, true],  // ERROR
+    ];
+
+
+    #[test]
+    fn runner_transitions() {
+        for (row, from) in RUNNER_STATUSES.iter().enumerate() {
+            for (column, to) in RUNNER_STATUSES.iter().enumerate() {
+                let allowed = runner_allows(
+                    &RunnerStatus::from_str(from).unwrap(),
+                    &RunnerStatus::from_str(to).unwrap(),
+                );
+                assert_eq!(allowed, RUNNER_TABLE[row][column], "runner {} -> {}", from, to);
+            }
+        }
+    }
+
+
+    #[test]
+    fn hardware_transitions() {
+        for (row, from) in HARDWARE_STATUSES.iter().enumerate() {
+            for (column, to) in HARDWARE_STATUSES.iter().enumerate() {
+                let allowed = hardware_allows(
+                    &HardwareStatus::from_str(from).unwrap(),
+                    &HardwareStatus::from_str(to).unwrap(),
+                );
+                assert_eq!(allowed, HARDWARE_TABLE[row][column], "hardware {} -> {}", from, to);
+            }
+        }
+    }
+
+
+    #[test]
+    fn rejection_reason() {
+        let error = check_runner("runner1", &RunnerStatus::RUNNING, &RunnerStatus::IDLE)
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Runner runner1 can not change from RUNNING to IDLE");
+
+        let error = check_hardware("rpi4", &HardwareStatus::CLAIMED, &HardwareStatus::CLAIMED)
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Hardware rpi4 can not change from CLAIMED to CLAIMED");
+
+        let error = TransitionError::new("Runner runner1", "ERROR", "RUNNING");
+        assert_eq!(error.0, "Runner runner1 can not change from ERROR to RUNNING");
+    }
+
+
+    #[test]
+    fn allowed_transitions_pass() {
+        assert!(check_runner("runner1", &RunnerStatus::IDLE, &RunnerStatus::RUNNING).is_ok());
+        assert!(check_hardware("rpi4", &HardwareStatus::FREE, &HardwareStatus::CLAIMED).is_ok());
+    }
+}
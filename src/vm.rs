@@ -7,13 +7,20 @@
 //
 
 
-use std::fs;
-use std::path;
-use std::io;
-use strum_macros::AsRefStr;
+mod command;
+mod libvirt;
+mod share;
+
+use anyhow::{bail, Result};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::tokio::{process, time};
+use rocket_okapi::okapi::{schemars, schemars::JsonSchema};
+use std::str::FromStr;
+use std::time::Duration;
 use std::{env, sync::LazyLock};
+use strum_macros::{AsRefStr, EnumString};
 
-use rocket::tokio::time::{sleep, Duration};
+use crate::{db, timestamp};
 
 
 
@@ -22,9 +29,64 @@ use rocket::tokio::time::{sleep, Duration};
 //------------------------------------------------------------------------------
 
 
-static COMMAND_DIR: LazyLock<String> = LazyLock::new(|| {
-    let default: &str = "/tmp/test";
-    env::var("COMMAND_SHARE").unwrap_or(default.to_string())
+/// Time the hypervisor side has to acknowledge a command
+pub static COMMAND_TIMEOUT: LazyLock<i64> = LazyLock::new(|| {
+    let input = env::var("VM_COMMAND_TIMEOUT").unwrap_or("10min".to_string());
+    timestamp::parse_duration(&input).unwrap_or(10 * 60) // Default value 10 Minutes
+});
+
+/// Time an external program like virsh may run before it is killed and the command
+/// counts as failed
+static PROCESS_TIMEOUT: LazyLock<i64> = LazyLock::new(|| {
+    let input = env::var("VM_PROCESS_TIMEOUT").unwrap_or("2min".to_string());
+    timestamp::parse_duration(&input).unwrap_or(2 * 60) // Default value 2 Minutes
+});
+
+/// Time between queueing a command and handing it to the backend, which gives a runner
+/// time to finish the request that caused e.g. its own reset
+pub static COMMAND_DELAY: LazyLock<i64> = LazyLock::new(|| {
+    let input = env::var("VM_COMMAND_DELAY").unwrap_or("30sec".to_string());
+    timestamp::parse_duration(&input).unwrap_or(30) // Default value 30 Seconds
+});
+
+/// Attempts to hand a command to the backend before it is given up
+pub static COMMAND_RETRIES: LazyLock<i64> = LazyLock::new(|| {
+    env::var("VM_COMMAND_RETRIES")
+        .ok()
+        .and_then(|input| input.parse().ok())
+        .unwrap_or(5)
+});
+
+/// Time finished commands are kept in the queue for inspection before they are deleted
+pub static COMMAND_RETENTION: LazyLock<i64> = LazyLock::new(|| {
+    let input = env::var("VM_COMMAND_RETENTION").unwrap_or("7day".to_string());
+    timestamp::parse_duration(&input).unwrap_or(7 * 24 * 60 * 60) // Default value 7 Days
+});
+
+/// First retry delay of a command the backend rejected, doubled on every attempt
+pub const RETRY_BACKOFF: i64 = 30;
+
+/// Number of snapshots kept per runner for rolling back, older ones are deleted
+pub static SNAPSHOT_RETENTION: LazyLock<usize> = LazyLock::new(|| {
+    env::var("SNAPSHOT_RETENTION")
+        .ok()
+        .and_then(|input| input.parse().ok())
+        .unwrap_or(3)
+});
+
+/// Age after which VMs get a new snapshot once they updated
+pub static SNAPSHOT_MAX_AGE: LazyLock<i64> = LazyLock::new(|| {
+    let input = env::var("SNAPSHOT_MAX_AGE").unwrap_or("30day".to_string());
+    timestamp::parse_duration(&input).unwrap_or(30 * 24 * 60 * 60) // Default value 30 Days
+});
+
+/// Backend of runners that do not select one themselves
+static DEFAULT_BACKEND: LazyLock<BackendKind> = LazyLock::new(|| {
+    let input = env::var("VM_BACKEND").unwrap_or("share".to_string());
+    BackendKind::from_str(&input).unwrap_or_else(|_| {
+        eprintln!("Unknown VM_BACKEND {}, falling back to share", input);
+        BackendKind::share
+    })
 });
 
 
@@ -34,41 +96,131 @@ static COMMAND_DIR: LazyLock<String> = LazyLock::new(|| {
 //------------------------------------------------------------------------------
 
 
-#[derive(Debug, AsRefStr)]
+#[derive(Debug, Clone, Copy, AsRefStr, PartialEq, EnumString)]
 #[allow(non_camel_case_types)]
 pub enum Command {
     start,
     stop,
     createsnap,
     revert,
+    deletesnap,
+    /// Removes the runner's registration from GitHub, done by the API instead of a backend
+    deregister,
 }
 
 
+#[derive(Debug, Clone, Copy, AsRefStr, PartialEq, EnumString, Serialize, Deserialize, JsonSchema)]
+#[allow(non_camel_case_types)]
+pub enum BackendKind {
+    share,
+    libvirt,
+    command,
+}
 
-//------------------------------------------------------------------------------
-// Utility Functions
-//------------------------------------------------------------------------------
 
+#[derive(Debug, AsRefStr, PartialEq, EnumString, Serialize, Deserialize, JsonSchema)]
+pub enum PowerState {
+    RUNNING,
+    STOPPED,
+    CRASHED,
+    UNKNOWN,
+}
+
+impl PowerState {
+    /// Parses the state as reported by the hypervisor side, e.g. in a share state file
+    fn from_report(report: &str) -> Self {
+        match report.trim().to_lowercase().as_str() {
+            "running" => PowerState::RUNNING,
+            "stopped" | "shut off" => PowerState::STOPPED,
+            "crashed" => PowerState::CRASHED,
+            _ => PowerState::UNKNOWN,
+        }
+    }
+}
 
-async fn touch(vm: String, command: Command) -> io::Result<()> {
 
-    let path = get_path(&vm, command);
+/// Result of a command as reported by the hypervisor side
+#[derive(Debug, PartialEq)]
+pub enum Acknowledgement {
+    Done,
+    Failed(String),
+}
 
-    sleep(Duration::from_secs(30)).await;
 
-    println!("Creating vm command file at: {:?}", &path);
-    fs::OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(&path)?;
-    Ok(())
+/// Outcome of the last command sent to a runner VM
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct CommandOutcome {
+    pub command: String,
+    pub status: db::CommandStatus,
+    pub message: Option<String>,
+    pub time: timestamp::Timestamp,
 }
 
 
-fn get_path(vm: &str, command: Command) -> path::PathBuf {
-    let dir: &str = COMMAND_DIR.as_ref();
-    return path::Path::new(&dir).join(format!("{}.{}", vm, command.as_ref()));
+/// Entry of the VM command queue of a runner
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct QueuedCommand {
+    pub id: i64,
+    pub command: String,
+    pub argument: Option<String>,
+    pub status: db::VmCommandStatus,
+    pub attempts: i64,
+    pub not_before: timestamp::Timestamp,
+    pub last_error: Option<String>,
+    pub created_at: timestamp::Timestamp,
+    pub updated_at: timestamp::Timestamp,
+}
+
+impl QueuedCommand {
+    pub fn from(record: db::VmCommandRecord) -> Self {
+        Self {
+            id: record.Id,
+            command: record.Command,
+            argument: record.Argument,
+            status: db::VmCommandStatus::from_str(&record.Status)
+                .expect("Invalid VM Command Status: Database Corruption"),
+            attempts: record.Attempts,
+            not_before: timestamp::Timestamp::from(record.NotBefore),
+            last_error: record.LastError,
+            created_at: timestamp::Timestamp::from(record.CreatedAt),
+            updated_at: timestamp::Timestamp::from(record.UpdatedAt),
+        }
+    }
+}
+
+
+/// Hypervisor specific way of controlling the runner VMs
+#[rocket::async_trait]
+pub trait VmBackend: Send + Sync {
+    async fn start(&self, vm: &str) -> Result<()>;
+    async fn stop(&self, vm: &str) -> Result<()>;
+    /// Without a name the snapshot is unnamed and only reachable as the current one
+    async fn snapshot(&self, vm: &str, snapshot: Option<&str>) -> Result<()>;
+    /// Without a name the VM goes back to the current snapshot
+    async fn revert(&self, vm: &str, snapshot: Option<&str>) -> Result<()>;
+    async fn delete_snapshot(&self, vm: &str, snapshot: &str) -> Result<()>;
+    async fn status(&self, vm: &str) -> Result<PowerState>;
+
+    /// Backends which only hand commands over learn about their outcome later and
+    /// return `None` while it is pending. All others are done once `exec` returns.
+    async fn acknowledgement(&self, _vm: &str, _command: Command) -> Result<Option<Acknowledgement>> {
+        Ok(Some(Acknowledgement::Done))
+    }
+
+    /// The argument is the snapshot name of the snapshot commands
+    async fn exec(&self, vm: &str, command: Command, argument: Option<&str>) -> Result<()> {
+        match command {
+            Command::start => self.start(vm).await,
+            Command::stop => self.stop(vm).await,
+            Command::createsnap => self.snapshot(vm, argument).await,
+            Command::revert => self.revert(vm, argument).await,
+            Command::deletesnap => match argument {
+                Some(snapshot) => self.delete_snapshot(vm, snapshot).await,
+                None => bail!("deletesnap requires a snapshot name"),
+            },
+            Command::deregister => bail!("deregister is not handled by VM backends"),
+        }
+    }
 }
 
 
@@ -77,23 +229,37 @@ fn get_path(vm: &str, command: Command) -> path::PathBuf {
 // VM CONTROL FUNCTIONS
 //------------------------------------------------------------------------------
 
-pub async fn exec_command(vm: &str, command: Command) {
-    rocket::tokio::spawn(touch(vm.to_string(), command));
-}
 
-pub async fn start(runner: &str) {
-    exec_command(runner, Command::start).await;
+/// Resolves the backend of a runner, falling back to the one selected via `VM_BACKEND`
+pub fn backend(kind: Option<BackendKind>) -> &'static dyn VmBackend {
+    match kind.unwrap_or(*DEFAULT_BACKEND) {
+        BackendKind::share => &share::ShareBackend,
+        BackendKind::libvirt => &libvirt::LibvirtBackend,
+        BackendKind::command => &command::CommandBackend,
+    }
 }
 
-
-pub async fn stop(runner: &str) {
-    exec_command(runner, Command::stop).await;
+pub fn default_backend() -> BackendKind {
+    *DEFAULT_BACKEND
 }
 
-pub async fn snapshot(runner: &str) {
-    exec_command(runner, Command::createsnap).await;
+/// Output of a backend program, which is killed once it exceeds `VM_PROCESS_TIMEOUT` so a
+/// hanging hypervisor does not hold up the command and reconcile tasks
+async fn output(program: &mut process::Command) -> Result<std::process::Output> {
+    let timeout = Duration::from_secs(*PROCESS_TIMEOUT as u64);
+    match time::timeout(timeout, program.kill_on_drop(true).output()).await {
+        Ok(output) => Ok(output?),
+        Err(_) => bail!(
+            "{} did not finish within {} seconds",
+            program.as_std().get_program().to_string_lossy(),
+            timeout.as_secs()
+        ),
+    }
 }
 
-pub async fn reset(runner: &str) {
-    exec_command(runner, Command::revert).await;
-}
\ No newline at end of file
+/// Names end up in command files and hypervisor command lines, a leading dash would be
+/// taken for an option
+pub fn valid_name(name: &str) -> bool {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.';
+    !name.is_empty() && !name.starts_with('-') && name.chars().all(allowed)
+}
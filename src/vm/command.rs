@@ -0,0 +1,116 @@
+//
+// Copyright (C) 2024, HENSOLDT Cyber GmbH
+// 
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// For commercial licensing, contact: info.cyber@hensoldt.net
+//
+
+
+use anyhow::{bail, Result};
+use rocket::tokio::process::Command as Process;
+use std::{env, sync::LazyLock};
+
+use super::{Command, PowerState, VmBackend};
+
+
+
+//------------------------------------------------------------------------------
+// Config
+//------------------------------------------------------------------------------
+
+
+/// Whitespace separated command line, `{runner}`, `{command}` and `{snapshot}` are
+/// substituted. Arguments left empty, like `{snapshot}` of other commands, are dropped.
+static COMMAND_TEMPLATE: LazyLock<String> = LazyLock::new(|| {
+    let default: &str = "/usr/local/bin/vmctl {runner} {command}";
+    env::var("VM_COMMAND_TEMPLATE").unwrap_or(default.to_string())
+});
+
+
+
+//------------------------------------------------------------------------------
+// Utility Functions
+//------------------------------------------------------------------------------
+
+
+async fn run(vm: &str, command: &str, snapshot: Option<&str>) -> Result<String> {
+    let args: Vec<String> = COMMAND_TEMPLATE
+        .split_whitespace()
+        .map(|arg| {
+            arg.replace("{runner}", vm)
+                .replace("{command}", command)
+                .replace("{snapshot}", snapshot.unwrap_or_default())
+        })
+        .filter(|arg| !arg.is_empty())
+        .collect();
+
+    println!("Running vm command: {}", args.join(" "));
+    let Some((program, args)) = args.split_first() else {
+        bail!("VM_COMMAND_TEMPLATE is empty");
+    };
+
+    let output = super::output(Process::new(program).args(args)).await?;
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+
+    if !output.status.success() {
+        let code = output.status.code().map_or("none".to_string(), |code| code.to_string());
+        bail!(
+            "{} {} failed with exit code {}: {}",
+            vm,
+            command,
+            code,
+            if stderr.trim().is_empty() { stdout.trim() } else { stderr.trim() }
+        );
+    }
+
+    if !stderr.trim().is_empty() {
+        eprintln!("{} {}: {}", vm, command, stderr.trim());
+    }
+    Ok(stdout)
+}
+
+
+
+//------------------------------------------------------------------------------
+// Backend
+//------------------------------------------------------------------------------
+
+
+/// Runs an external command for every VM command, configured via `VM_COMMAND_TEMPLATE`
+pub struct CommandBackend;
+
+#[rocket::async_trait]
+impl VmBackend for CommandBackend {
+    async fn start(&self, vm: &str) -> Result<()> {
+        run(vm, Command::start.as_ref(), None).await?;
+        Ok(())
+    }
+
+    async fn stop(&self, vm: &str) -> Result<()> {
+        run(vm, Command::stop.as_ref(), None).await?;
+        Ok(())
+    }
+
+    async fn snapshot(&self, vm: &str, snapshot: Option<&str>) -> Result<()> {
+        run(vm, Command::createsnap.as_ref(), snapshot).await?;
+        Ok(())
+    }
+
+    async fn revert(&self, vm: &str, snapshot: Option<&str>) -> Result<()> {
+        run(vm, Command::revert.as_ref(), snapshot).await?;
+        Ok(())
+    }
+
+    async fn delete_snapshot(&self, vm: &str, snapshot: &str) -> Result<()> {
+        run(vm, Command::deletesnap.as_ref(), Some(snapshot)).await?;
+        Ok(())
+    }
+
+    /// Runs the template with `{command}` set to `status`, which prints one of
+    /// running, stopped or crashed
+    async fn status(&self, vm: &str) -> Result<PowerState> {
+        Ok(PowerState::from_report(&run(vm, "status", None).await?))
+    }
+}
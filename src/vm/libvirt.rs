@@ -0,0 +1,101 @@
+//
+// Copyright (C) 2024, HENSOLDT Cyber GmbH
+// 
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// For commercial licensing, contact: info.cyber@hensoldt.net
+//
+
+
+use anyhow::{bail, Result};
+use rocket::tokio::process::Command as Process;
+use std::{env, sync::LazyLock};
+
+use super::{PowerState, VmBackend};
+
+
+
+//------------------------------------------------------------------------------
+// Config
+//------------------------------------------------------------------------------
+
+
+/// Use `test:///default` to try the backend without a hypervisor
+static LIBVIRT_URI: LazyLock<String> = LazyLock::new(|| {
+    let default: &str = "qemu:///system";
+    env::var("LIBVIRT_URI").unwrap_or(default.to_string())
+});
+
+
+
+//------------------------------------------------------------------------------
+// Utility Functions
+//------------------------------------------------------------------------------
+
+
+async fn virsh(args: &[&str]) -> Result<String> {
+    println!("Running virsh -c {} {}", *LIBVIRT_URI, args.join(" "));
+    let mut program = Process::new("virsh");
+    program.arg("-c").arg(LIBVIRT_URI.as_str()).args(args);
+    let output = super::output(&mut program).await?;
+
+    if !output.status.success() {
+        bail!(
+            "virsh {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+
+
+//------------------------------------------------------------------------------
+// Backend
+//------------------------------------------------------------------------------
+
+
+/// Controls VMs through libvirt, the domain is named after the runner Id
+pub struct LibvirtBackend;
+
+#[rocket::async_trait]
+impl VmBackend for LibvirtBackend {
+    async fn start(&self, vm: &str) -> Result<()> {
+        virsh(&["start", vm]).await?;
+        Ok(())
+    }
+
+    async fn stop(&self, vm: &str) -> Result<()> {
+        virsh(&["shutdown", vm]).await?;
+        Ok(())
+    }
+
+    async fn snapshot(&self, vm: &str, snapshot: Option<&str>) -> Result<()> {
+        // Becomes the current snapshot, which an unnamed revert goes back to
+        match snapshot {
+            Some(snapshot) => virsh(&["snapshot-create-as", vm, snapshot]).await?,
+            None => virsh(&["snapshot-create-as", vm]).await?,
+        };
+        Ok(())
+    }
+
+    async fn revert(&self, vm: &str, snapshot: Option<&str>) -> Result<()> {
+        match snapshot {
+            Some(snapshot) => virsh(&["snapshot-revert", vm, snapshot, "--running"]).await?,
+            None => virsh(&["snapshot-revert", vm, "--current", "--running"]).await?,
+        };
+        Ok(())
+    }
+
+    async fn delete_snapshot(&self, vm: &str, snapshot: &str) -> Result<()> {
+        virsh(&["snapshot-delete", vm, snapshot]).await?;
+        Ok(())
+    }
+
+    async fn status(&self, vm: &str) -> Result<PowerState> {
+        // Besides running, shut off and crashed, domstate reports transitional states
+        // like paused or in shutdown, which end up as UNKNOWN
+        Ok(PowerState::from_report(&virsh(&["domstate", vm]).await?))
+    }
+}
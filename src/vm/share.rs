@@ -0,0 +1,150 @@
+//
+// Copyright (C) 2024, HENSOLDT Cyber GmbH
+// 
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// For commercial licensing, contact: info.cyber@hensoldt.net
+//
+
+
+use anyhow::Result;
+use std::fs;
+use std::path;
+use std::io;
+use std::time::Duration;
+use std::{env, sync::LazyLock};
+
+use super::{Acknowledgement, Command, PowerState, VmBackend};
+
+
+
+//------------------------------------------------------------------------------
+// Config
+//------------------------------------------------------------------------------
+
+
+static COMMAND_DIR: LazyLock<String> = LazyLock::new(|| {
+    let default: &str = "/tmp/test";
+    env::var("COMMAND_SHARE").unwrap_or(default.to_string())
+});
+
+/// Host scripts which write `.done` and `.failed` files opt in with `1`, commands are
+/// taken as done once the command file is written otherwise
+static ACKNOWLEDGE: LazyLock<bool> = LazyLock::new(|| {
+    env::var("VM_SHARE_ACK").is_ok_and(|ack| ack == "1")
+});
+
+/// State files not touched for this long are no longer trusted
+const STATE_VALIDITY: Duration = Duration::from_secs(10 * 60);
+
+
+
+//------------------------------------------------------------------------------
+// Utility Functions
+//------------------------------------------------------------------------------
+
+
+/// The file contains the snapshot name of the snapshot commands, empty otherwise
+fn touch(vm: &str, command: Command, argument: Option<&str>) -> io::Result<()> {
+
+    let path = get_path(vm, command);
+
+    // Results of an earlier run of the same command must not be taken for this one
+    for result in ["done", "failed"] {
+        let _ = fs::remove_file(get_result_path(vm, command, result));
+    }
+
+    println!("Creating vm command file at: {:?}", &path);
+    fs::write(&path, argument.unwrap_or_default())?;
+    Ok(())
+}
+
+
+fn get_path(vm: &str, command: Command) -> path::PathBuf {
+    let dir: &str = COMMAND_DIR.as_ref();
+    return path::Path::new(&dir).join(format!("{}.{}", vm, command.as_ref()));
+}
+
+
+/// Written by the hypervisor script once it executed the command, `.failed` contains
+/// the reason
+fn get_result_path(vm: &str, command: Command, result: &str) -> path::PathBuf {
+    let dir: &str = COMMAND_DIR.as_ref();
+    return path::Path::new(&dir).join(format!("{}.{}.{}", vm, command.as_ref(), result));
+}
+
+
+fn get_state_path(vm: &str) -> path::PathBuf {
+    let dir: &str = COMMAND_DIR.as_ref();
+    return path::Path::new(&dir).join(format!("{}.state", vm));
+}
+
+
+
+//------------------------------------------------------------------------------
+// Backend
+//------------------------------------------------------------------------------
+
+
+/// Controls VMs by touching `<vm>.<command>` files in `COMMAND_SHARE`, which are
+/// picked up by a script on the hypervisor
+pub struct ShareBackend;
+
+#[rocket::async_trait]
+impl VmBackend for ShareBackend {
+    async fn start(&self, vm: &str) -> Result<()> {
+        Ok(touch(vm, Command::start, None)?)
+    }
+
+    async fn stop(&self, vm: &str) -> Result<()> {
+        Ok(touch(vm, Command::stop, None)?)
+    }
+
+    async fn snapshot(&self, vm: &str, snapshot: Option<&str>) -> Result<()> {
+        Ok(touch(vm, Command::createsnap, snapshot)?)
+    }
+
+    async fn revert(&self, vm: &str, snapshot: Option<&str>) -> Result<()> {
+        Ok(touch(vm, Command::revert, snapshot)?)
+    }
+
+    async fn delete_snapshot(&self, vm: &str, snapshot: &str) -> Result<()> {
+        Ok(touch(vm, Command::deletesnap, Some(snapshot))?)
+    }
+
+    /// Reads `<vm>.state`, which the hypervisor script keeps up to date with one of
+    /// running, stopped or crashed. A missing or outdated file means UNKNOWN.
+    async fn status(&self, vm: &str) -> Result<PowerState> {
+        let path = get_state_path(vm);
+        if !path.exists() {
+            return Ok(PowerState::UNKNOWN);
+        }
+
+        let age = fs::metadata(&path)?.modified()?.elapsed().unwrap_or_default();
+        if age > STATE_VALIDITY {
+            return Ok(PowerState::UNKNOWN);
+        }
+        Ok(PowerState::from_report(&fs::read_to_string(&path)?))
+    }
+
+    async fn acknowledgement(&self, vm: &str, command: Command) -> Result<Option<Acknowledgement>> {
+        if !*ACKNOWLEDGE {
+            return Ok(Some(Acknowledgement::Done));
+        }
+
+        let done = get_result_path(vm, command, "done");
+        if done.exists() {
+            fs::remove_file(&done)?;
+            return Ok(Some(Acknowledgement::Done));
+        }
+
+        let failed = get_result_path(vm, command, "failed");
+        if failed.exists() {
+            let message = fs::read_to_string(&failed).unwrap_or_default();
+            fs::remove_file(&failed)?;
+            return Ok(Some(Acknowledgement::Failed(message.trim().to_string())));
+        }
+
+        Ok(None)
+    }
+}
@@ -0,0 +1,292 @@
+//
+// Copyright (C) 2024, HENSOLDT Cyber GmbH
+// 
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// For commercial licensing, contact: info.cyber@hensoldt.net
+//
+
+
+use hmac::{Hmac, Mac};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::status;
+use rocket::serde::{json, Deserialize};
+use rocket_db_pools::sqlx::SqliteConnection;
+use rocket_okapi::{
+    gen::OpenApiGenerator,
+    request::{OpenApiFromRequest, RequestHeaderInput},
+};
+use sha2::Sha256;
+use std::{env, sync::LazyLock};
+
+use crate::{db, runners};
+
+
+
+//------------------------------------------------------------------------------
+// Config
+//------------------------------------------------------------------------------
+
+
+/// Secret configured for the webhook on GitHub, deliveries are refused without it
+static WEBHOOK_SECRET: LazyLock<Option<String>> = LazyLock::new(|| {
+    dotenv::dotenv().ok();
+    env::var("GITHUB_WEBHOOK_SECRET").ok()
+});
+
+
+
+//------------------------------------------------------------------------------
+// Data Structures
+//------------------------------------------------------------------------------
+
+
+/// GitHub headers of a webhook delivery
+pub struct WebhookHeaders {
+    event: String,
+    signature: Option<String>,
+    delivery: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for WebhookHeaders {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        match headers.get_one("X-GitHub-Event") {
+            Some(event) => request::Outcome::Success(Self {
+                event: event.to_string(),
+                signature: headers.get_one("X-Hub-Signature-256").map(str::to_string),
+                delivery: headers.get_one("X-GitHub-Delivery").map(str::to_string),
+            }),
+            None => request::Outcome::Error((Status::BadRequest, ())),
+        }
+    }
+}
+
+impl<'r> OpenApiFromRequest<'r> for WebhookHeaders {
+    fn from_request_input(
+        _gen: &mut OpenApiGenerator,
+        _name: String,
+        _required: bool,
+    ) -> rocket_okapi::Result<RequestHeaderInput> {
+        Ok(RequestHeaderInput::None)
+    }
+}
+
+
+#[derive(Debug, Deserialize)]
+struct WorkflowJobEvent {
+    action: String,
+    workflow_job: WorkflowJob,
+    repository: Repository,
+}
+
+
+#[derive(Debug, Deserialize)]
+struct WorkflowJob {
+    id: i64,
+    run_id: i64,
+    name: String,
+    runner_name: Option<String>,
+    html_url: Option<String>,
+}
+
+
+#[derive(Debug, Deserialize)]
+struct Repository {
+    full_name: String,
+}
+
+
+
+//------------------------------------------------------------------------------
+// Utility Functions
+//------------------------------------------------------------------------------
+
+
+/// Checks the `sha256=<hex>` HMAC GitHub computes over the raw payload
+fn verify_signature(secret: &str, payload: &[u8], signature: &str) -> bool {
+    let Some(signature) = signature.strip_prefix("sha256=") else {
+        return false;
+    };
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(payload);
+    mac.verify_slice(&signature).is_ok()
+}
+
+
+/// Deliveries are refused unless a secret is configured and they are signed with it
+fn authenticate(
+    secret: Option<&str>,
+    headers: &WebhookHeaders,
+    payload: &[u8],
+) -> Result<(), status::Custom<String>> {
+    let Some(secret) = secret else {
+        eprintln!("GITHUB_WEBHOOK_SECRET is not set, refusing webhook deliveries");
+        return Err(status::Custom(Status::InternalServerError, String::new()));
+    };
+
+    let signature = headers.signature.as_deref().unwrap_or_default();
+    if !verify_signature(secret, payload, signature) {
+        eprintln!("Invalid signature of webhook delivery {:?}", headers.delivery);
+        return Err(status::Custom(Status::Unauthorized, "Invalid signature".to_string()));
+    }
+    Ok(())
+}
+
+
+async fn workflow_job(
+    db: &mut SqliteConnection,
+    event: WorkflowJobEvent,
+) -> Result<Status, status::Custom<String>> {
+    let job = event.workflow_job;
+
+    // Queued jobs are not assigned to a runner yet, other runners are not ours
+    let Some(runner) = job.runner_name else {
+        println!("Job {} of {} is {}", job.name, event.repository.full_name, event.action);
+        return Ok(Status::Ok);
+    };
+    if !db::runner_exists(db, &runner).await {
+        return Ok(Status::Ok);
+    }
+
+    let job = runners::RunnerJob {
+        id: job.id,
+        run_id: job.run_id,
+        name: job.name,
+        repository: event.repository.full_name,
+        url: job.html_url,
+    };
+
+    let result = match event.action.as_str() {
+        "in_progress" => runners::runner_job_started(db, &runner, job).await,
+        "completed" => runners::runner_job_completed(db, &runner, job).await,
+        _ => Ok(Status::Ok),
+    };
+    result.map_err(|e| status::Custom(Status::Conflict, e.to_string()))
+}
+
+
+
+//------------------------------------------------------------------------------
+// Webhook Endpoint Logic
+//------------------------------------------------------------------------------
+
+
+pub async fn handle(
+    db: &mut SqliteConnection,
+    headers: WebhookHeaders,
+    payload: &[u8],
+) -> Result<Status, status::Custom<String>> {
+    authenticate(WEBHOOK_SECRET.as_deref(), &headers, payload)?;
+
+    match headers.event.as_str() {
+        "ping" => Ok(Status::Ok),
+        "workflow_job" => match json::from_slice::<WorkflowJobEvent>(payload) {
+            Ok(event) => workflow_job(db, event).await,
+            Err(e) => Err(status::Custom(Status::BadRequest, e.to_string())),
+        },
+        _ => Ok(Status::Accepted), // Not subscribed to, nothing to do
+    }
+}
+
+
+
+//------------------------------------------------------------------------------
+// Tests
+//------------------------------------------------------------------------------
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "It's a Secret to Everybody";
+    const PAYLOAD: &[u8] = b"Hello, World!";
+    const RUNNER: &str = "runner-1";
+
+    /// Example of the GitHub documentation on validating webhook deliveries
+    const SIGNATURE: &str =
+        "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+
+    fn headers(signature: Option<&str>) -> WebhookHeaders {
+        WebhookHeaders {
+            event: "workflow_job".to_string(),
+            signature: signature.map(str::to_string),
+            delivery: None,
+        }
+    }
+
+    fn event(action: &str) -> WorkflowJobEvent {
+        WorkflowJobEvent {
+            action: action.to_string(),
+            workflow_job: WorkflowJob {
+                id: 1,
+                run_id: 2,
+                name: "build".to_string(),
+                runner_name: Some(RUNNER.to_string()),
+                html_url: None,
+            },
+            repository: Repository {
+                full_name: "org/repo".to_string(),
+            },
+        }
+    }
+
+
+    #[test]
+    fn valid_signature() {
+        assert!(verify_signature(SECRET, PAYLOAD, SIGNATURE));
+        assert!(authenticate(Some(SECRET), &headers(Some(SIGNATURE)), PAYLOAD).is_ok());
+    }
+
+
+    #[test]
+    fn wrong_signature() {
+        assert!(!verify_signature("another secret", PAYLOAD, SIGNATURE));
+        assert!(!verify_signature(SECRET, b"Hello, World?", SIGNATURE));
+        assert!(!verify_signature(SECRET, PAYLOAD, "sha256=not hex"));
+
+        let refused = authenticate(Some(SECRET), &headers(None), PAYLOAD).unwrap_err();
+        assert_eq!(refused.0, Status::Unauthorized);
+    }
+
+
+    #[test]
+    fn missing_prefix() {
+        let signature = SIGNATURE.strip_prefix("sha256=").unwrap();
+        assert!(!verify_signature(SECRET, PAYLOAD, signature));
+    }
+
+
+    #[test]
+    fn missing_secret() {
+        let refused = authenticate(None, &headers(Some(SIGNATURE)), PAYLOAD).unwrap_err();
+        assert_eq!(refused.0, Status::InternalServerError);
+    }
+
+
+    #[rocket::async_test]
+    async fn job_dispatch() {
+        let mut db = db::test_db().await;
+        db::insert_runner(&mut db, RUNNER, db::RunnerStatus::IDLE).await;
+
+        assert_eq!(workflow_job(&mut db, event("in_progress")).await.unwrap(), Status::Ok);
+        let info = db::get_runner_info(&mut db, RUNNER).await;
+        assert_eq!(info.status, db::RunnerStatus::RUNNING);
+        assert_eq!(info.job.map(|job| job.id), Some(1));
+
+        assert_eq!(workflow_job(&mut db, event("completed")).await.unwrap(), Status::Ok);
+        let info = db::get_runner_info(&mut db, RUNNER).await;
+        assert_eq!(info.status, db::RunnerStatus::RESETTING);
+        assert_eq!(info.job, None);
+    }
+}